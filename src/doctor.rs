@@ -0,0 +1,712 @@
+use std::{fmt, net::IpAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{StatusCode, Url};
+use serde_json::json;
+use tokio::net::TcpStream;
+
+use crate::{
+    error::ErrorBody,
+    transport::{Request, Response, Transport},
+    Error,
+};
+
+/// How far our clock can drift from datadog's before we warn about it
+const MAX_SKEW: chrono::Duration = chrono::Duration::seconds(10);
+/// How long to wait when checking the proxy accepts connections
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks that dogtail can reach the API and read logs with the keys it's been given, to turn
+/// a failing run into something actionable
+pub struct Doctor {
+    transport: Arc<dyn Transport>,
+    domain: String,
+    api_key: Result<String, String>,
+    app_key: Result<String, String>,
+    proxy: Option<String>,
+    no_proxy: Option<String>,
+    /// Reads environment variables, so tests can set them without touching the process's
+    env: fn(&str) -> Option<String>,
+}
+
+/// The result of one check
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    /// What to do about it, if anything
+    pub advice: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+    /// Not run, because an earlier check it depends on failed
+    Skip,
+}
+
+impl Doctor {
+    /// `api_key` and `app_key` are the keys, or why they couldn't be read. `proxy` is the proxy
+    /// requests are configured to go through, if it was set explicitly rather than from the env
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        domain: String,
+        api_key: Result<String, String>,
+        app_key: Result<String, String>,
+        proxy: Option<String>,
+    ) -> Self {
+        Self {
+            transport,
+            domain,
+            api_key,
+            app_key,
+            proxy,
+            no_proxy: None,
+            env: |var| std::env::var(var).ok(),
+        }
+    }
+
+    /// The hosts to connect to directly rather than through `proxy`, if they were set
+    /// explicitly rather than from NO_PROXY
+    pub fn with_no_proxy(mut self, no_proxy: String) -> Self {
+        self.no_proxy = Some(no_proxy);
+        self
+    }
+
+    pub async fn run(&self) -> Vec<Check> {
+        let mut checks = vec![self.check_key("API key", &self.api_key)];
+        checks.push(self.check_key("APP key", &self.app_key));
+        checks.push(self.check_proxy().await);
+
+        let api_key = match &self.api_key {
+            Ok(key) => self.check_api_key(key).await,
+            Err(_) => skip("API key valid", "API key couldn't be read"),
+        };
+        let api_key_valid = api_key.outcome == Outcome::Pass;
+        checks.push(api_key);
+
+        let (Ok(api), Ok(app)) = (&self.api_key, &self.app_key) else {
+            checks.push(skip("APP key valid", "keys couldn't be read"));
+            checks.push(skip("Logs read permission", "keys couldn't be read"));
+            return checks;
+        };
+        if !api_key_valid {
+            checks.push(skip("APP key valid", "API key couldn't be validated"));
+            checks.push(skip(
+                "Logs read permission",
+                "API key couldn't be validated",
+            ));
+            return checks;
+        }
+
+        checks.push(self.check_app_key(api, app).await);
+        let (logs, response) = self.check_logs_read(api, app).await;
+        checks.push(logs);
+        if let Some(response) = response {
+            checks.push(rate_limit(&response));
+            checks.push(clock_skew(&response));
+        }
+        checks
+    }
+
+    fn check_key(&self, name: &'static str, key: &Result<String, String>) -> Check {
+        match key {
+            Ok(key) => pass(name, format!("read, {} characters", key.len())),
+            Err(e) => fail(
+                name,
+                e.clone(),
+                "Set DD_API_KEY and DD_APP_KEY, or pass --api-key / --app-key / --credential-command",
+            ),
+        }
+    }
+
+    async fn check_proxy(&self) -> Check {
+        let name = "Proxy";
+        let env = |vars: &[&str]| {
+            vars.iter()
+                .find_map(|var| (self.env)(var).filter(|v| !v.trim().is_empty()))
+        };
+        // The API is https, so this is the proxy reqwest picks for it, where ALL_PROXY wins
+        let proxy = self
+            .proxy
+            .clone()
+            .or_else(|| env(&["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy"]));
+        let no_proxy = self
+            .no_proxy
+            .clone()
+            .or_else(|| env(&["NO_PROXY", "no_proxy"]));
+        let host = Url::parse(&format!("https://{}", self.domain))
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| self.domain.clone());
+
+        let Some(proxy) = proxy else {
+            if let Some(http_proxy) = env(&["HTTP_PROXY", "http_proxy"]) {
+                return warn(
+                    name,
+                    format!(
+                        "HTTP_PROXY is {}, but it's only used for http urls, and the API is https",
+                        redact_url(&http_proxy)
+                    ),
+                    "If requests to the API need to go through it, set HTTPS_PROXY too, or pass --proxy",
+                );
+            }
+            return pass(name, "none configured, connecting directly".to_string());
+        };
+        if no_proxy.is_some_and(|no_proxy| in_no_proxy(&no_proxy, &host)) {
+            return pass(
+                name,
+                format!(
+                    "{} is in NO_PROXY, connecting directly rather than through {}",
+                    host,
+                    redact_url(&proxy)
+                ),
+            );
+        }
+
+        let url = match Url::parse(&proxy) {
+            Ok(url) => url,
+            Err(e) => {
+                return fail(
+                    name,
+                    format!("{} isn't a valid url: {}", redact_url(&proxy), e),
+                    "Give the proxy as a url, e.g. http://proxy.internal:3128",
+                )
+            }
+        };
+        let shown = redact_url(&proxy);
+        let Some(host) = url.host_str() else {
+            return fail(
+                name,
+                format!("{} has no host", shown),
+                "Give the proxy as a url, e.g. http://proxy.internal:3128",
+            );
+        };
+        let port = url.port_or_known_default().unwrap_or(80);
+        match tokio::time::timeout(PROXY_TIMEOUT, TcpStream::connect((host, port))).await {
+            Ok(Ok(_)) => pass(name, format!("{} is accepting connections", shown)),
+            Ok(Err(e)) => fail(
+                name,
+                format!("couldn't connect to {}: {}", shown, e),
+                "Check the proxy address, and that it's reachable from this machine",
+            ),
+            Err(_) => fail(
+                name,
+                format!("timed out connecting to {}", shown),
+                "Check the proxy address, and that nothing is blocking connections to it",
+            ),
+        }
+    }
+
+    async fn check_api_key(&self, api_key: &str) -> Check {
+        let name = "API key valid";
+        let request = Request::get(format!("https://{}/api/v1/validate", self.domain))
+            .secret_header("DD-API-KEY", api_key);
+        let response = match request {
+            Ok(request) => self.transport.execute(request).await,
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(r) if r.status.is_success() => pass(name, format!("accepted by {}", self.domain)),
+            Ok(r) if r.status == StatusCode::FORBIDDEN => fail(
+                name,
+                format!("rejected by {}: {}", self.domain, error_body(&r)),
+                &format!(
+                    "Check the key is an API key rather than an APP key, and that it's for the site {} \
+                     (keys only work on their own site, e.g. api.datadoghq.com for US1, api.datadoghq.eu \
+                     for EU) - pass -d to use another",
+                    self.domain
+                ),
+            ),
+            Ok(r) => fail(
+                name,
+                format!("unexpected {} from {}: {}", r.status, self.domain, error_body(&r)),
+                "Check the domain is a datadog API domain, e.g. api.datadoghq.com",
+            ),
+            Err(e) => unreachable_api(name, &self.domain, e),
+        }
+    }
+
+    async fn check_app_key(&self, api_key: &str, app_key: &str) -> Check {
+        let name = "APP key valid";
+        // Any valid APP key can see the APP keys of the user that owns it
+        let url = format!(
+            "https://{}/api/v2/current_user/application_keys?page[size]=1",
+            self.domain
+        );
+        let response = match authed(Request::get(url), api_key, app_key) {
+            Ok(request) => self.transport.execute(request).await,
+            Err(e) => Err(e),
+        };
+        match response {
+            Ok(r) if r.status.is_success() => pass(name, "accepted".to_string()),
+            Ok(r) if r.status == StatusCode::FORBIDDEN || r.status == StatusCode::UNAUTHORIZED => {
+                warn(
+                    name,
+                    format!("rejected: {}", error_body(&r)),
+                    "Check the APP key exists and belongs to this org. If it's a scoped key, this is \
+                     expected, and only the logs read check below matters",
+                )
+            }
+            Ok(r) => warn(
+                name,
+                format!("unexpected {}: {}", r.status, error_body(&r)),
+                "The logs read check below is the one that matters",
+            ),
+            Err(e) => unreachable_api(name, &self.domain, e),
+        }
+    }
+
+    /// Run the smallest query we can, returning the response for the checks that need one
+    async fn check_logs_read(&self, api_key: &str, app_key: &str) -> (Check, Option<Response>) {
+        let name = "Logs read permission";
+        let url = format!("https://{}/api/v2/logs/events/search", self.domain);
+        let query = json!({
+            "filter": { "from": "now-1m", "to": "now", "query": "*" },
+            "page": { "limit": 1 },
+        });
+        let response = match authed(Request::post(url).json(query), api_key, app_key) {
+            Ok(request) => self.transport.execute(request).await,
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => return (unreachable_api(name, &self.domain, e), None),
+        };
+
+        let check = match response.status {
+            s if s.is_success() => pass(name, "a test query succeeded".to_string()),
+            StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED => fail(
+                name,
+                format!("a test query was refused: {}", error_body(&response)),
+                "Check the APP key is valid, that its user has the logs_read_data permission, and if \
+                 it's a scoped key, that it has the logs_read_data scope",
+            ),
+            StatusCode::TOO_MANY_REQUESTS => warn(
+                name,
+                "a test query was rate limited".to_string(),
+                "Something else is using up the rate limit for these keys - consider --rate-limit-file \
+                 to share it between dogtail processes",
+            ),
+            s => fail(
+                name,
+                format!("unexpected {}: {}", s, error_body(&response)),
+                "Check the datadog status page, and try again",
+            ),
+        };
+        (check, Some(response))
+    }
+}
+
+/// Report the rate-limit headers of the logs query
+fn rate_limit(response: &Response) -> Check {
+    let name = "Rate limit";
+    let header = |key: &str| {
+        response
+            .headers
+            .get(format!("x-ratelimit-{}", key))
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    let (Some(limit), Some(period), Some(remaining), Some(reset)) = (
+        header("limit"),
+        header("period"),
+        header("remaining"),
+        header("reset"),
+    ) else {
+        return warn(
+            name,
+            "no rate-limit headers in the response".to_string(),
+            "Dogtail paces itself with these headers - if a proxy is stripping them, it will need to \
+             pass them through",
+        );
+    };
+    let detail = format!(
+        "{} of {} requests left in this {}s period, resetting in {}s",
+        remaining, limit, period, reset
+    );
+    match remaining.parse::<u32>() {
+        Ok(0) => warn(
+            name,
+            detail,
+            "The budget is spent, so dogtail will wait for the reset before querying",
+        ),
+        _ => pass(name, detail),
+    }
+}
+
+/// Compare our clock to the Date header of the logs query
+fn clock_skew(response: &Response) -> Check {
+    let name = "Clock skew";
+    let Some(date) = response
+        .headers
+        .get("date")
+        .and_then(|d| d.to_str().ok())
+        .and_then(|d| DateTime::parse_from_rfc2822(d).ok())
+    else {
+        return warn(
+            name,
+            "no Date header in the response to compare with".to_string(),
+            "Make sure this machine's clock is synced, e.g. with NTP",
+        );
+    };
+    let skew = Utc::now() - date.with_timezone(&Utc);
+    let detail = format!(
+        "local clock is {}s {} datadog's",
+        skew.num_seconds().abs(),
+        if skew >= chrono::Duration::zero() {
+            "ahead of"
+        } else {
+            "behind"
+        }
+    );
+    if skew.abs() > MAX_SKEW {
+        warn(
+            name,
+            detail,
+            "Follow windows are based on the local clock. Sync it (e.g. with NTP), or if it's ahead, \
+             pass --delay to stay clear of the future",
+        )
+    } else {
+        pass(name, detail)
+    }
+}
+
+fn authed(request: Request, api_key: &str, app_key: &str) -> Result<Request, Error> {
+    request
+        .header("Accept", "application/json")?
+        .secret_header("DD-API-KEY", api_key)?
+        .secret_header("DD-APPLICATION-KEY", app_key)
+}
+
+fn unreachable_api(name: &'static str, domain: &str, e: Error) -> Check {
+    // The whole chain of http errors repeats itself a lot, so just show what went wrong at the bottom
+    let chain = e.to_string();
+    let mut root: &dyn std::error::Error = &e;
+    while let Some(source) = root.source() {
+        root = source;
+    }
+    let detail = format!("couldn't reach {}: {}", domain, root);
+    let advice = if chain.contains("certificate") {
+        "The connection's certificate wasn't trusted - if you're behind a proxy that intercepts TLS, \
+         pass its CA with --ca-bundle"
+    } else if chain.contains("timed out") {
+        "Check your network, or raise --connect-timeout / --timeout"
+    } else if chain.contains("dns error") {
+        "The domain couldn't be resolved - check it's right (e.g. api.datadoghq.com), and if DNS only works \
+         through a proxy here, set HTTPS_PROXY or pass --proxy"
+    } else {
+        "Check your network and the domain. If you need a proxy, set HTTPS_PROXY or pass --proxy"
+    };
+    fail(name, detail, advice)
+}
+
+fn error_body(response: &Response) -> ErrorBody {
+    ErrorBody::parse(&response.body)
+}
+
+// Whether reqwest would connect to `host` directly, given a NO_PROXY style list of domains (which
+// match their subdomains too), ip addresses and networks, or "*" for everything
+fn in_no_proxy(no_proxy: &str, host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let ip = host.parse::<IpAddr>().ok();
+    no_proxy.split(',').map(str::trim).any(|entry| {
+        if let Some(ip) = ip {
+            return match entry.split_once('/') {
+                Some((network, bits)) => in_network(ip, network, bits),
+                None => entry.parse() == Ok(ip),
+            };
+        }
+        entry == "*"
+            || entry.trim_start_matches('.') == host
+            || host
+                .strip_suffix(entry.trim_start_matches('.'))
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+fn in_network(ip: IpAddr, network: &str, bits: &str) -> bool {
+    let (Ok(network), Ok(bits)) = (network.parse::<IpAddr>(), bits.parse::<u32>()) else {
+        return false;
+    };
+    let (ip, network, width) = match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            (u32::from(ip) as u128, u32::from(network) as u128, 32)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => (u128::from(ip), u128::from(network), 128),
+        _ => return false,
+    };
+    if bits > width {
+        return false;
+    }
+    let shift = width - bits;
+    ip.checked_shr(shift).unwrap_or(0) == network.checked_shr(shift).unwrap_or(0)
+}
+
+// Don't print the password of an authenticating proxy
+fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if parsed.password().is_some() => {
+            let _ = parsed.set_password(Some("****"));
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+fn pass(name: &'static str, detail: String) -> Check {
+    Check {
+        name,
+        outcome: Outcome::Pass,
+        detail,
+        advice: None,
+    }
+}
+
+fn warn(name: &'static str, detail: String, advice: &str) -> Check {
+    Check {
+        name,
+        outcome: Outcome::Warn,
+        detail,
+        advice: Some(advice.to_string()),
+    }
+}
+
+fn fail(name: &'static str, detail: String, advice: &str) -> Check {
+    Check {
+        name,
+        outcome: Outcome::Fail,
+        detail,
+        advice: Some(advice.to_string()),
+    }
+}
+
+fn skip(name: &'static str, reason: &str) -> Check {
+    Check {
+        name,
+        outcome: Outcome::Skip,
+        detail: format!("skipped, {}", reason),
+        advice: None,
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Outcome::Pass => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+            Outcome::Skip => "skip",
+        };
+        // Pad, so the check names line up
+        f.pad(s)
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{:>4}] {}: {}", self.outcome, self.name, self.detail)?;
+        if let Some(advice) = &self.advice {
+            write!(f, "\n       -> {}", advice)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::transport::fake::{response, with_rate_limit, FakeTransport};
+
+    const VALIDATE: &str = "https://api.example.com/api/v1/validate";
+    const APP_KEYS: &str =
+        "https://api.example.com/api/v2/current_user/application_keys?page[size]=1";
+    const LOGS: &str = "https://api.example.com/api/v2/logs/events/search";
+
+    fn logs_response(status: u16, remaining: u32, date: DateTime<Utc>) -> Response {
+        let mut response = with_rate_limit(response(status, json!({"data": []})), remaining, 30);
+        let date = HeaderValue::from_str(&date.to_rfc2822()).unwrap();
+        response.headers.insert("date", date);
+        response
+    }
+
+    fn working() -> FakeTransport {
+        FakeTransport::default()
+            .with_response(VALIDATE, response(200, json!({"valid": true})))
+            .with_response(APP_KEYS, response(200, json!({"data": []})))
+            .with_response(LOGS, logs_response(200, 99, Utc::now()))
+    }
+
+    fn doctor(transport: FakeTransport) -> Doctor {
+        let mut doctor = Doctor::new(
+            Arc::new(transport),
+            "api.example.com".to_string(),
+            Ok("api".to_string()),
+            Ok("app".to_string()),
+            None,
+        );
+        doctor.env = |_| None;
+        doctor
+    }
+
+    fn outcomes(checks: &[Check]) -> Vec<(&'static str, Outcome)> {
+        checks.iter().map(|c| (c.name, c.outcome)).collect()
+    }
+
+    fn outcome(checks: &[Check], name: &str) -> Outcome {
+        checks.iter().find(|c| c.name == name).unwrap().outcome
+    }
+
+    #[tokio::test]
+    async fn passes_with_working_keys() {
+        let checks = doctor(working()).run().await;
+        assert_eq!(
+            outcomes(&checks),
+            [
+                ("API key", Outcome::Pass),
+                ("APP key", Outcome::Pass),
+                ("Proxy", Outcome::Pass),
+                ("API key valid", Outcome::Pass),
+                ("APP key valid", Outcome::Pass),
+                ("Logs read permission", Outcome::Pass),
+                ("Rate limit", Outcome::Pass),
+                ("Clock skew", Outcome::Pass),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_checks_that_need_valid_keys() {
+        let mut unread = doctor(working());
+        unread.app_key = Err("DD_APP_KEY isn't set".to_string());
+        let checks = unread.run().await;
+        assert_eq!(outcome(&checks, "APP key"), Outcome::Fail);
+        assert_eq!(outcome(&checks, "API key valid"), Outcome::Pass);
+        assert_eq!(outcome(&checks, "Logs read permission"), Outcome::Skip);
+
+        let transport = FakeTransport::default()
+            .with_response(VALIDATE, response(403, json!({"errors": ["Forbidden"]})));
+        let checks = doctor(transport).run().await;
+        assert_eq!(outcome(&checks, "API key valid"), Outcome::Fail);
+        assert_eq!(outcome(&checks, "APP key valid"), Outcome::Skip);
+        assert_eq!(outcome(&checks, "Logs read permission"), Outcome::Skip);
+        assert_eq!(checks.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn reports_refused_and_rate_limited_queries() {
+        let transport = FakeTransport::default()
+            .with_response(VALIDATE, response(200, json!({})))
+            .with_response(APP_KEYS, response(403, json!({"errors": ["Forbidden"]})))
+            .with_response(LOGS, logs_response(403, 99, Utc::now()));
+        let checks = doctor(transport).run().await;
+        // A scoped APP key can't list keys, so only the query matters
+        assert_eq!(outcome(&checks, "APP key valid"), Outcome::Warn);
+        assert_eq!(outcome(&checks, "Logs read permission"), Outcome::Fail);
+
+        let transport = FakeTransport::default()
+            .with_response(VALIDATE, response(200, json!({})))
+            .with_response(APP_KEYS, response(200, json!({})))
+            .with_response(LOGS, logs_response(429, 0, Utc::now()));
+        let checks = doctor(transport).run().await;
+        assert_eq!(outcome(&checks, "Logs read permission"), Outcome::Warn);
+        assert_eq!(outcome(&checks, "Rate limit"), Outcome::Warn);
+    }
+
+    #[tokio::test]
+    async fn warns_about_missing_headers_and_clock_skew() {
+        let transport = FakeTransport::default()
+            .with_response(VALIDATE, response(200, json!({})))
+            .with_response(APP_KEYS, response(200, json!({})))
+            .with_response(LOGS, response(200, Value::Null));
+        let checks = doctor(transport).run().await;
+        assert_eq!(outcome(&checks, "Rate limit"), Outcome::Warn);
+        assert_eq!(outcome(&checks, "Clock skew"), Outcome::Warn);
+
+        let behind = Utc::now() + chrono::Duration::minutes(2);
+        let transport = FakeTransport::default()
+            .with_response(VALIDATE, response(200, json!({})))
+            .with_response(APP_KEYS, response(200, json!({})))
+            .with_response(LOGS, logs_response(200, 99, behind));
+        let checks = doctor(transport).run().await;
+        let skew = checks.iter().find(|c| c.name == "Clock skew").unwrap();
+        assert_eq!(skew.outcome, Outcome::Warn);
+        assert!(skew.detail.contains("behind"), "{}", skew.detail);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_api_is_unreachable() {
+        let checks = doctor(FakeTransport::default()).run().await;
+        let check = checks.iter().find(|c| c.name == "API key valid").unwrap();
+        assert_eq!(check.outcome, Outcome::Fail);
+        assert!(check.detail.starts_with("couldn't reach api.example.com"));
+    }
+
+    #[tokio::test]
+    async fn checks_the_proxy_reqwest_would_use() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = format!("http://user:secret@{}", listener.local_addr().unwrap());
+
+        let mut explicit = doctor(working());
+        explicit.proxy = Some(proxy.clone());
+        let check = explicit.check_proxy().await;
+        assert_eq!(check.outcome, Outcome::Pass);
+        assert!(check.detail.contains("accepting connections"));
+        assert!(!check.detail.contains("secret"), "{}", check.detail);
+
+        let check = explicit
+            .with_no_proxy("localhost, .example.com".to_string())
+            .check_proxy()
+            .await;
+        assert!(check.detail.contains("is in NO_PROXY"), "{}", check.detail);
+
+        let mut from_env = doctor(working());
+        from_env.env = |var| match var {
+            "HTTPS_PROXY" => Some("http://127.0.0.1:1".to_string()),
+            _ => None,
+        };
+        assert_eq!(from_env.check_proxy().await.outcome, Outcome::Fail);
+        from_env.env = |var| match var {
+            "https_proxy" => Some("http://127.0.0.1:1".to_string()),
+            "no_proxy" => Some("api.example.com".to_string()),
+            _ => None,
+        };
+        assert_eq!(from_env.check_proxy().await.outcome, Outcome::Pass);
+        from_env.env = |var| match var {
+            "HTTP_PROXY" => Some("http://127.0.0.1:1".to_string()),
+            _ => None,
+        };
+        assert_eq!(from_env.check_proxy().await.outcome, Outcome::Warn);
+    }
+
+    #[test]
+    fn matches_no_proxy_like_reqwest() {
+        let cases = [
+            ("example.com", "api.example.com", true),
+            (".example.com", "api.example.com", true),
+            ("example.com", "example.com", true),
+            ("ample.com", "api.example.com", false),
+            ("other.com, *", "api.example.com", true),
+            ("10.0.0.0/8", "10.1.2.3", true),
+            ("10.0.0.0/8", "11.1.2.3", false),
+            ("10.1.2.3", "10.1.2.3", true),
+            ("::1", "[::1]", true),
+            ("fd00::/8", "[fd12::1]", true),
+            ("", "api.example.com", false),
+        ];
+        for (no_proxy, host, expected) in cases {
+            assert_eq!(
+                in_no_proxy(no_proxy, host),
+                expected,
+                "{} {}",
+                no_proxy,
+                host
+            );
+        }
+    }
+}
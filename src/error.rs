@@ -0,0 +1,266 @@
+use std::{fmt, io, path::PathBuf};
+
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use crate::sink::SinkError;
+
+/// Everything that can go wrong in dogtail
+#[derive(Debug)]
+pub enum Error {
+    /// The API refused our keys (401), or they lack a permission we need (403)
+    Unauthorized {
+        status: StatusCode,
+        body: ErrorBody,
+        source_id: Option<String>,
+    },
+    /// The API told us to slow down (429). The tailer waits and retries these itself
+    RateLimited {
+        body: ErrorBody,
+        source_id: Option<String>,
+    },
+    /// Any other error response from the API
+    Api {
+        status: StatusCode,
+        body: ErrorBody,
+        source_id: Option<String>,
+    },
+    /// A response that wasn't what we expected, e.g. invalid json, a missing field, or missing
+    /// rate-limit headers
+    Malformed {
+        message: String,
+        source_id: Option<String>,
+    },
+    /// The request couldn't be made at all, e.g. because of the network, a proxy, or TLS
+    Transport {
+        source: Box<dyn std::error::Error + Send + Sync>,
+        source_id: Option<String>,
+    },
+    /// The API keys couldn't be read
+    Credentials(String),
+    /// Some input didn't make sense, e.g. a time that couldn't be parsed, or a CA bundle with no
+    /// certificates in it
+    Invalid(String),
+    /// Reading or writing a file failed
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// A sink stopped consuming events
+    Sink(SinkError),
+    /// Whoever was receiving events went away
+    Closed,
+    /// A replay ran out of recorded responses. The tailer ends its stream when it sees this,
+    /// since it's how a replay finishes rather than a failure
+    Exhausted,
+}
+
+/// The body of an error response. Datadog errors look like {"errors": ["..."]}, and those
+/// messages are pulled out where they're present
+#[derive(Debug, Clone)]
+pub struct ErrorBody {
+    pub errors: Vec<String>,
+    pub raw: String,
+}
+
+impl Error {
+    /// Build an error from an unsuccessful response
+    pub fn from_response(status: StatusCode, body: &[u8]) -> Self {
+        let body = ErrorBody::parse(body);
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Error::Unauthorized {
+                status,
+                body,
+                source_id: None,
+            },
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited {
+                body,
+                source_id: None,
+            },
+            _ => Error::Api {
+                status,
+                body,
+                source_id: None,
+            },
+        }
+    }
+
+    pub fn malformed(message: impl Into<String>) -> Self {
+        Error::Malformed {
+            message: message.into(),
+            source_id: None,
+        }
+    }
+
+    pub fn transport(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Error::Transport {
+            source: source.into(),
+            source_id: None,
+        }
+    }
+
+    pub fn io(path: impl Into<Option<PathBuf>>, source: io::Error) -> Self {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+
+    /// Note which source (e.g. which of a tailer's named queries) the error came from
+    pub fn with_source_id(mut self, id: Option<&str>) -> Self {
+        if let Some(slot) = self.source_id_mut() {
+            *slot = id.map(|id| id.to_string());
+        }
+        self
+    }
+
+    pub fn source_id(&self) -> Option<&str> {
+        match self {
+            Error::Unauthorized { source_id, .. }
+            | Error::RateLimited { source_id, .. }
+            | Error::Api { source_id, .. }
+            | Error::Malformed { source_id, .. }
+            | Error::Transport { source_id, .. } => source_id.as_deref(),
+            _ => None,
+        }
+    }
+
+    fn source_id_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            Error::Unauthorized { source_id, .. }
+            | Error::RateLimited { source_id, .. }
+            | Error::Api { source_id, .. }
+            | Error::Malformed { source_id, .. }
+            | Error::Transport { source_id, .. } => Some(source_id),
+            _ => None,
+        }
+    }
+
+    /// The status of the response, if the error came from one
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Error::Unauthorized { status, .. } | Error::Api { status, .. } => Some(*status),
+            Error::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            _ => None,
+        }
+    }
+
+    /// The body of the response, if the error came from one
+    pub fn body(&self) -> Option<&ErrorBody> {
+        match self {
+            Error::Unauthorized { body, .. }
+            | Error::RateLimited { body, .. }
+            | Error::Api { body, .. } => Some(body),
+            _ => None,
+        }
+    }
+
+    /// True if we stopped because whoever was reading the output went away, rather than
+    /// because of a failure
+    pub fn is_closed(&self) -> bool {
+        match self {
+            Error::Closed => true,
+            Error::Sink(e) => e.is_closed(),
+            _ => false,
+        }
+    }
+}
+
+impl ErrorBody {
+    pub fn parse(body: &[u8]) -> Self {
+        let raw = String::from_utf8_lossy(body).into_owned();
+        let errors = match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Object(mut body)) => match body.remove("errors") {
+                Some(Value::Array(errors)) => errors
+                    .into_iter()
+                    .map(|e| match e {
+                        Value::String(s) => s,
+                        e => e.to_string(),
+                    })
+                    .collect(),
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+        ErrorBody { errors, raw }
+    }
+}
+
+impl fmt::Display for ErrorBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.errors[..] {
+            [] => write!(f, "{}", self.raw),
+            errors => write!(f, "{}", errors.join(", ")),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(id) = self.source_id() {
+            write!(f, "query {}: ", id)?;
+        }
+        match self {
+            Error::Unauthorized { status, body, .. } => {
+                write!(f, "unauthorized ({}): {}", status, body)
+            }
+            Error::RateLimited { body, .. } => write!(f, "rate limited: {}", body),
+            Error::Api { status, body, .. } => write!(f, "API error ({}): {}", status, body),
+            Error::Malformed { message, .. } => write!(f, "unexpected response: {}", message),
+            Error::Transport { source, .. } => {
+                // Http errors tend to put what actually went wrong (e.g. connection refused) a few
+                // levels down, so show all of them. Some already include their causes, so skip
+                // any that have been shown
+                let mut message = source.to_string();
+                let mut cause = source.source();
+                while let Some(e) = cause {
+                    let e_message = e.to_string();
+                    if !message.contains(&e_message) {
+                        message = format!("{}: {}", message, e_message);
+                    }
+                    cause = e.source();
+                }
+                write!(f, "request failed: {}", message)
+            }
+            Error::Credentials(message) => write!(f, "{}", message),
+            Error::Invalid(message) => write!(f, "{}", message),
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Sink(e) => write!(f, "{}", e),
+            Error::Closed => write!(f, "receiver closed"),
+            Error::Exhausted => write!(f, "recording exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport { source, .. } => Some(source.as_ref()),
+            Error::Io { source, .. } => Some(source),
+            Error::Sink(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<SinkError> for Error {
+    fn from(e: SinkError) -> Self {
+        Error::Sink(e)
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::transport(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::malformed(e.to_string())
+    }
+}
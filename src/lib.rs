@@ -1,32 +1,52 @@
-use reqwest::{Client, RequestBuilder};
+use chrono::DateTime;
 use serde_json::Value;
+use transport::Request;
 
+pub mod doctor;
+pub mod error;
 pub mod logs;
+pub mod ratelimit;
+pub mod redact;
+pub mod reorder;
+pub mod secret;
+pub mod shard;
 pub mod sink;
 pub mod tailer;
+pub mod time;
+pub mod transform;
+pub mod transport;
+
+pub use error::Error;
 
 /// A thing which knows how talk to some subset of the datadog API - more or less the part of
 /// dogtail that implements some endpoints schema
 pub trait Source: Send + Sync {
     /// Construct a query to send to the API. If None is returned, the tailer will stop, dropping
     /// itself and the associated channel.
-    fn construct_query(&mut self, client: &Client) -> Option<RequestBuilder>;
+    fn construct_query(&mut self) -> Option<Request>;
     /// Extract the results from the response body. This should handle deduplication of events,
     /// if needed
-    fn extract_results(&mut self, body: Value) -> Result<Vec<Value>, anyhow::Error>;
+    fn extract_results(&mut self, body: Value) -> Result<Vec<Value>, Error>;
     /// Extract the next url from the response body - this is fairly standard across the datadog API,
     /// so we provide a default implementation
-    fn extract_next<'a>(&mut self, body: &'a Value) -> Result<Option<String>, anyhow::Error> {
-        let Some(next) = body.get("links").map(|l| l.get("next")).flatten() else {
+    fn extract_next(&mut self, body: &Value) -> Result<Option<String>, Error> {
+        let Some(next) = body.get("links").and_then(|l| l.get("next")) else {
             return Ok(None);
         };
         next.as_str()
-            .ok_or(anyhow::anyhow!("Next url not a string"))
+            .ok_or(Error::malformed("Next url not a string"))
             .map(|s| Some(s.to_string()))
     }
 
     /// Get the batch size for this source
     fn get_batch_size(&mut self) -> usize;
+
+    /// Whether the source has caught up with the present. While it hasn't (e.g. it's backfilling
+    /// from some point in the past), the tailer knows there's more to fetch, so it doesn't hold
+    /// back between queries beyond what the rate limit requires
+    fn caught_up(&self) -> bool {
+        true
+    }
 }
 
 // Kinda json-pointer, but not really
@@ -41,6 +61,46 @@ impl JsonKey {
         }
         Some(current.clone())
     }
+
+    /// Set the value at this key, creating any objects along the way that don't exist yet.
+    /// If something along the way isn't an object, nothing is set, and false is returned
+    pub fn set(&self, event: &mut Value, value: Value) -> bool {
+        let Some((last, parents)) = self.0.split_last() else {
+            return false;
+        };
+        let mut current = event;
+        for key in parents {
+            let Some(fields) = current.as_object_mut() else {
+                return false;
+            };
+            current = fields
+                .entry(key.clone())
+                .or_insert_with(|| Value::Object(Default::default()));
+        }
+        match current.as_object_mut() {
+            Some(fields) => {
+                fields.insert(last.clone(), value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the value at this key, returning it
+    pub fn remove(&self, event: &mut Value) -> Option<Value> {
+        let (last, parents) = self.0.split_last()?;
+        let mut current = event;
+        for key in parents {
+            current = current.get_mut(key)?;
+        }
+        current.as_object_mut()?.remove(last)
+    }
+}
+
+impl std::fmt::Display for JsonKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join("."))
+    }
 }
 
 impl From<String> for JsonKey {
@@ -54,3 +114,73 @@ impl From<&str> for JsonKey {
         JsonKey(s.split('.').map(|s| s.to_string()).collect())
     }
 }
+
+/// A named value to extract from each event, for sinks that write to some kind of table
+#[derive(Clone)]
+pub struct Column {
+    pub name: String,
+    pub key: JsonKey,
+    /// The type of the column. If None, sinks that need one infer it from the events they see
+    pub kind: Option<ColumnType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    String,
+    Int,
+    Float,
+    Bool,
+    Timestamp,
+}
+
+impl Column {
+    pub fn new(name: String, key: JsonKey, kind: Option<ColumnType>) -> Self {
+        Column { name, key, kind }
+    }
+}
+
+impl ColumnType {
+    /// Pick the narrowest type that fits all of the given values, ignoring nulls. Strings that are all
+    /// rfc3339 timestamps are treated as timestamps
+    pub fn infer<'a>(values: impl IntoIterator<Item = &'a Value>) -> Self {
+        let mut kind = None;
+        for value in values {
+            let this = match value {
+                Value::Null => continue,
+                Value::Bool(_) => ColumnType::Bool,
+                Value::Number(n) if n.is_i64() || n.is_u64() => ColumnType::Int,
+                Value::Number(_) => ColumnType::Float,
+                Value::String(s) if DateTime::parse_from_rfc3339(s).is_ok() => {
+                    ColumnType::Timestamp
+                }
+                _ => ColumnType::String,
+            };
+            kind = Some(match (kind, this) {
+                (None, this) => this,
+                (Some(k), this) if k == this => k,
+                (Some(ColumnType::Int), ColumnType::Float)
+                | (Some(ColumnType::Float), ColumnType::Int) => ColumnType::Float,
+                _ => ColumnType::String,
+            });
+        }
+        kind.unwrap_or(ColumnType::String)
+    }
+}
+
+impl std::str::FromStr for ColumnType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(ColumnType::String),
+            "int" => Ok(ColumnType::Int),
+            "float" => Ok(ColumnType::Float),
+            "bool" => Ok(ColumnType::Bool),
+            "timestamp" => Ok(ColumnType::Timestamp),
+            _ => Err(Error::Invalid(format!(
+                "Unknown column type {}, expected one of string, int, float, bool, timestamp",
+                s
+            ))),
+        }
+    }
+}
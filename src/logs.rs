@@ -1,27 +1,86 @@
 use std::collections::{HashMap, HashSet};
 
 use chrono::{DateTime, Duration, Utc};
-use reqwest::{Client, RequestBuilder};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
+use tracing::{info, warn};
 
-use crate::{JsonKey, Source};
+use crate::{time, transport::Request, Error, JsonKey, Source};
 
 pub struct LogSource<Mode> {
     search_url: String,
     query: String,
     seen_event_ids: HashSet<String>, // We don't want to return the same event twice
     mode: Mode,
+    last_window_end: Option<DateTime<Utc>>,
+    previous_window_end: Option<DateTime<Utc>>,
+    stats: Option<WindowStats>,
 }
 
-/// Produces time windows, overlapping by 10 seconds, forever. Useful
+/// Produces the time windows a [LogSource] queries, and optionally adapts them based on
+/// how querying the previous ones went
+pub trait WindowMode: Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> {
+    /// Called with the stats of each window once it's been fully fetched
+    fn record(&mut self, _stats: &WindowStats) {}
+
+    /// False if the last window was cut short of the present, so the next one can be fetched
+    /// straight away. See [Source::caught_up]
+    fn caught_up(&self) -> bool {
+        true
+    }
+
+    /// True if the window just produced goes back over history already queried, e.g. a rescan,
+    /// rather than being the next in sequence. Lag is only measured between windows in sequence
+    fn revisiting(&self) -> bool {
+        false
+    }
+}
+
+/// What fetching a single window looked like
+#[derive(Debug, Default, Clone)]
+pub struct WindowStats {
+    /// The number of pages it took to fetch the window
+    pub pages: usize,
+    /// The number of events in the window we hadn't seen before
+    pub events: usize,
+    /// The longest it took any new event to be indexed, judging by events turning up with
+    /// a timestamp before the end of the previous window
+    pub max_lag: Option<Duration>,
+}
+
+/// Produces time windows, overlapping by 10 seconds (by default), forever. Useful
 /// for constantly following logs
 pub struct Follow {
     next_window_start: DateTime<Utc>,
     next_window_end: Option<DateTime<Utc>>,
+    max_window: Option<Duration>,
+    last_window: Option<(Duration, bool)>, // How long the last window was, and if it was cut short
+    overlap: Duration,
+    min_overlap: Duration,
+    adaptive: bool,
+    delay: Duration,
+    rescan: Option<Rescan>,
 }
 
+/// A periodic re-query of a longer stretch of recent history, to pick up events indexed
+/// too late for the overlap between windows to catch
+struct Rescan {
+    tail: Duration,
+    every: Duration,
+    last: DateTime<Utc>,
+    in_progress: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    recovered: usize,
+}
+
+/// If an adaptive or backfilling follow needs more pages than this for a window, the windows shrink
+const TARGET_PAGES: usize = 5;
+/// Follows never shrink their windows below this many seconds past the overlap
+const MIN_WINDOW: i64 = 10;
+/// Adaptive follows never grow their overlap beyond this, no matter how late events turn up
+const MAX_OVERLAP: i64 = 300;
+
 /// Produces a single time window, and then stops. Useful for taking
 /// a snapshot of logs from a given period.
+#[derive(Clone)]
 pub struct Snapshot {
     next_window_start: DateTime<Utc>,
     next_window_end: Option<DateTime<Utc>>,
@@ -34,18 +93,32 @@ impl<Mode> LogSource<Mode> {
             query,
             seen_event_ids: HashSet::new(),
             mode,
+            last_window_end: None,
+            previous_window_end: None,
+            stats: None,
         }
     }
 }
 
 impl<Mode> Source for LogSource<Mode>
 where
-    Mode: Iterator<Item = (DateTime<Utc>, DateTime<Utc>)> + Send + Sync,
+    Mode: WindowMode + Send + Sync,
 {
-    fn construct_query(&mut self, client: &Client) -> Option<RequestBuilder> {
-        let builder = client.post(&self.search_url);
+    fn construct_query(&mut self) -> Option<Request> {
+        let builder = Request::post(&self.search_url);
+
+        // We're only asked for a new query once the last one has been completely fetched
+        if let Some(stats) = self.stats.take() {
+            self.mode.record(&stats);
+        }
 
         let (start, end) = self.mode.next()?;
+        self.previous_window_end = if self.mode.revisiting() {
+            None
+        } else {
+            self.last_window_end.replace(end)
+        };
+        self.stats = Some(WindowStats::default());
 
         let from = start.to_rfc3339();
         let to = end.to_rfc3339();
@@ -61,48 +134,171 @@ where
             },
             "sort": "timestamp"
         });
-        Some(builder.json(&query))
+        Some(builder.json(query))
     }
 
-    fn extract_results(&mut self, mut body: Value) -> Result<Vec<Value>, anyhow::Error> {
+    fn extract_results(&mut self, mut body: Value) -> Result<Vec<Value>, Error> {
         let Some(events) = body.get_mut("data") else {
             return Ok(vec![]);
         };
-        let mut events: Vec<_> = std::mem::take(
+        let events = std::mem::take(
             events
                 .as_array_mut()
-                .ok_or(anyhow::anyhow!("Log query data not a list"))?,
-        )
-        .into_iter()
-        .map(|e| unpack_tags(e))
-        .collect();
+                .ok_or(Error::malformed("Log query data not a list"))?,
+        );
 
-        events.retain(|event| {
-            self.seen_event_ids
-                .insert(event["id"].as_str().unwrap().to_string())
-        });
+        let mut parsed = Vec::with_capacity(events.len());
+        for event in events {
+            if !event.is_object() {
+                warn!("Skipping event, expected an object, got {}", event);
+                continue;
+            }
+            // Events without an id (which the API shouldn't send) are told apart by their content
+            let id = match event["id"].as_str() {
+                Some(id) => id.to_string(),
+                None => event.to_string(),
+            };
+            if self.seen_event_ids.insert(id) {
+                parsed.push(unpack_tags(event));
+            }
+        }
 
-        Ok(events)
+        if let Some(stats) = self.stats.as_mut() {
+            stats.pages += 1;
+            stats.events += parsed.len();
+            if let Some(previous_end) = self.previous_window_end {
+                let lag = parsed
+                    .iter()
+                    .filter_map(|e| time::parse_timestamp(&e["attributes"]["timestamp"]))
+                    .filter(|t| *t < previous_end)
+                    .map(|t| previous_end - t)
+                    .max();
+                stats.max_lag = stats.max_lag.max(lag);
+            }
+        }
+
+        Ok(parsed)
     }
 
     fn get_batch_size(&mut self) -> usize {
         1000
     }
+
+    fn caught_up(&self) -> bool {
+        self.mode.caught_up()
+    }
+}
+
+/// A log event, as returned by the logs search API. Fields the event doesn't have, or that
+/// aren't the expected type, are None (or empty). A [LogSource] emits events as json, which
+/// formats, split keys and transforms work on, and [crate::tailer::Tailer::into_log_events]
+/// reads them into these. The json is kept as it is, for fields that aren't modelled here
+#[derive(Debug, Clone)]
+pub struct LogEvent {
+    pub id: Option<String>,
+    /// The name of the query that found the event, if the tailer runs named queries
+    pub query: Option<String>,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub status: Option<String>,
+    pub service: Option<String>,
+    pub host: Option<String>,
+    pub message: Option<String>,
+    /// The event's tags, split into keys and values. A key can have several values, e.g. for
+    /// "team:a" and "team:b", or none, for a tag like "canary"
+    pub tags: HashMap<String, Vec<String>>,
+    /// The event's tags as the API returned them, e.g. "team:a"
+    pub raw_tags: Vec<String>,
+    /// The event's custom attributes, i.e. those that aren't reserved, like the ones above
+    pub attributes: Map<String, Value>,
+    raw: Value,
+}
+
+impl LogEvent {
+    /// Read an event emitted by a [LogSource]. This only fails if the event isn't a json object
+    pub fn from_json(raw: Value) -> Result<Self, Error> {
+        if !raw.is_object() {
+            return Err(Error::malformed(format!(
+                "Expected a log event to be an object, got {}",
+                raw
+            )));
+        }
+        let attributes = &raw["attributes"];
+        let string = |v: &Value| v.as_str().map(|s| s.to_string());
+        Ok(LogEvent {
+            id: string(&raw["id"]),
+            query: string(&raw["query"]),
+            timestamp: time::parse_timestamp(&attributes["timestamp"]),
+            status: string(&attributes["status"]),
+            service: string(&attributes["service"]),
+            host: string(&attributes["host"]),
+            message: string(&attributes["message"]),
+            tags: attributes["tags"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .map(|(k, v)| {
+                    let values = match v {
+                        Value::Array(values) => values.iter().filter_map(string).collect(),
+                        v => string(v).into_iter().collect(),
+                    };
+                    (k.clone(), values)
+                })
+                .collect(),
+            raw_tags: attributes["raw_tags"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(string)
+                .collect(),
+            attributes: attributes["attributes"]
+                .as_object()
+                .cloned()
+                .unwrap_or_default(),
+            raw,
+        })
+    }
+
+    /// The event as json, e.g. to pull out fields that aren't modelled here
+    pub fn raw(&self) -> &Value {
+        &self.raw
+    }
+
+    pub fn into_raw(self) -> Value {
+        self.raw
+    }
 }
 
+/// Unpack the event's tags into a map, e.g. ["env:prod", "team:a", "team:b", "canary"] becomes
+/// {"env": "prod", "team": ["a", "b"], "canary": null}. Values are everything after the first
+/// colon, so "url:http://x" keeps its whole url. The tags as the API returned them are kept
+/// under "raw_tags"
 fn unpack_tags(mut event: Value) -> Value {
-    if let Some(tags) = event["attributes"]["tags"].as_array() {
-        let mut unpacked = HashMap::new();
-        for tag in tags {
-            if let Some(str) = tag.as_str() {
-                let mut split = str.split(':');
-                let key = split.next().unwrap();
-                let value = split.next().unwrap_or_default();
-                unpacked.insert(key, value);
+    let Some(tags) = event["attributes"]["tags"].as_array() else {
+        return event;
+    };
+    let mut unpacked = Map::new();
+    for tag in tags.iter().filter_map(Value::as_str) {
+        let (key, value) = match tag.split_once(':') {
+            Some((key, value)) => (key, Value::String(value.to_string())),
+            None => (tag, Value::Null),
+        };
+        match unpacked.get_mut(key) {
+            None => {
+                unpacked.insert(key.to_string(), value);
+            }
+            Some(Value::Array(values)) => {
+                if !values.contains(&value) {
+                    values.push(value);
+                }
+            }
+            Some(existing) if *existing != value => {
+                *existing = Value::Array(vec![existing.take(), value]);
             }
+            Some(_) => {}
         }
-        event["attributes"]["tags"] = json!(unpacked);
     }
+    let raw = std::mem::replace(&mut event["attributes"]["tags"], Value::Object(unpacked));
+    event["attributes"]["raw_tags"] = raw;
     event
 }
 
@@ -153,14 +349,14 @@ impl LogFormat {
         }
 
         for key in rest {
-            output.push_str(&sep);
+            output.push_str(sep);
             if let Some(value) = key.get(event) {
                 output.push_str(value.as_str().unwrap_or(format!("{:?}", value).as_str()));
             } else {
                 output.push_str("KEY_NOT_FOUND");
             }
         }
-        return output;
+        output
     }
 }
 
@@ -171,6 +367,137 @@ impl Follow {
         Self {
             next_window_start: start,
             next_window_end: end,
+            max_window: None,
+            last_window: None,
+            overlap: Duration::seconds(10),
+            min_overlap: Duration::seconds(10),
+            adaptive: false,
+            delay: Duration::zero(),
+            rescan: None,
+        }
+    }
+
+    /// Follow logs starting from a point in the past. The time between `start` and now is
+    /// backfilled one window after another, as fast as the rate limit allows, and once caught
+    /// up this carries on like any other follow. Backfill windows start at `backfill_window`,
+    /// and are sized to the query as they go, like an [Follow::adaptive] follow's: halved when
+    /// they take more than a few pages, and doubled when they fit in one, but never shorter than
+    /// the overlap plus a few seconds. Windows still overlap, so the source's deduplication keeps
+    /// the hand-off free of duplicates.
+    pub fn since(start: DateTime<Utc>, backfill_window: Duration) -> Self {
+        Self {
+            next_window_start: start,
+            next_window_end: None,
+            max_window: Some(backfill_window),
+            ..Self::new(0)
+        }
+    }
+
+    /// Overlap consecutive windows by `overlap` rather than 10 seconds. The longer the overlap, the
+    /// later an event can be indexed and still be picked up
+    pub fn with_overlap(mut self, overlap: Duration) -> Self {
+        self.overlap = overlap;
+        self.min_overlap = overlap;
+        self
+    }
+
+    /// Adapt windows to the query. Windows that take more than a few pages to fetch are halved
+    /// (so a busy query doesn't fall behind chasing an enormous page chain), and windows that fit
+    /// in a single page are doubled, back up to following all the way to now. The overlap grows to
+    /// cover the latest events have been seen arriving, and shrinks back to the configured overlap
+    /// while nothing arrives late.
+    pub fn adaptive(mut self) -> Self {
+        self.adaptive = true;
+        self
+    }
+
+    /// Stop windows `delay` short of now, giving datadog time to index events before we go looking
+    /// for them
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Every `every`, re-query the last `tail` of history (up to the delay) as an extra window.
+    /// Events already returned are deduplicated by the source, so only events indexed too late
+    /// to be caught by the normal windows come out of it, and the number recovered is logged.
+    /// Rescans wait until the windows have caught up with now, so they don't jump ahead of a
+    /// backfill.
+    pub fn with_rescan(mut self, tail: Duration, every: Duration) -> Self {
+        self.rescan = Some(Rescan {
+            tail,
+            every,
+            last: Utc::now(),
+            in_progress: None,
+            recovered: 0,
+        });
+        self
+    }
+}
+
+impl WindowMode for Follow {
+    fn caught_up(&self) -> bool {
+        !self.last_window.is_some_and(|(_, cut_short)| cut_short)
+    }
+
+    fn revisiting(&self) -> bool {
+        self.rescan
+            .as_ref()
+            .is_some_and(|rescan| rescan.in_progress.is_some())
+    }
+
+    fn record(&mut self, stats: &WindowStats) {
+        if let Some(rescan) = self.rescan.as_mut() {
+            if let Some((start, end)) = rescan.in_progress.take() {
+                rescan.recovered += stats.events;
+                info!(
+                    "Re-scan of {} to {} recovered {} late events ({} in total)",
+                    start, end, stats.events, rescan.recovered
+                );
+                // Rescans are much longer than normal windows, so they'd throw off the sizing
+                return;
+            }
+        }
+
+        if self.adaptive {
+            let max_overlap = Duration::seconds(MAX_OVERLAP).max(self.min_overlap);
+            match stats.max_lag {
+                Some(lag) if lag * 3 / 2 > self.overlap => {
+                    self.overlap = (lag * 3 / 2).min(max_overlap);
+                    info!(
+                        "Events arriving up to {}s late, overlapping windows by {}s",
+                        lag.num_seconds(),
+                        self.overlap.num_seconds()
+                    );
+                }
+                Some(_) => {}
+                None => self.overlap = (self.overlap * 9 / 10).max(self.min_overlap),
+            }
+        }
+
+        let Some((span, cut_short)) = self.last_window else {
+            return;
+        };
+        // Windows that are catching up (i.e. backfilling) are always sized to the query
+        if !self.adaptive && !cut_short {
+            return;
+        }
+        if stats.pages > TARGET_PAGES {
+            // Windows overlap, so they have to be longer than the overlap to make any progress
+            let shrunk = (span / 2).max(self.overlap + Duration::seconds(MIN_WINDOW));
+            info!(
+                "Window took {} pages, shrinking to {}s",
+                stats.pages,
+                shrunk.num_seconds()
+            );
+            self.max_window = Some(shrunk);
+        } else if stats.pages <= 1 {
+            self.max_window = match self.max_window {
+                // Once a window makes it all the way to now, there's nothing to grow into
+                Some(_) if !cut_short => None,
+                Some(max) => Some(max * 2),
+                None => None,
+            };
         }
     }
 }
@@ -179,27 +506,69 @@ impl Iterator for Follow {
     type Item = (DateTime<Utc>, DateTime<Utc>);
 
     fn next(&mut self) -> Option<Self::Item> {
+        let now = Utc::now();
+        let reached_now = self.last_window.is_some_and(|(_, cut_short)| !cut_short);
+        if let Some(rescan) = self.rescan.as_mut().filter(|_| reached_now) {
+            if now - rescan.last >= rescan.every {
+                rescan.last = now;
+                let window = (now - self.delay - rescan.tail, now - self.delay);
+                rescan.in_progress = Some(window);
+                return Some(window);
+            }
+        }
+
         let start = self.next_window_start;
-        let end = self.next_window_end.take().unwrap_or(Utc::now());
+        let mut end = self.next_window_end.take().unwrap_or(now - self.delay);
 
-        // Use time windows that overlap by 10 seconds to avoid missing events
-        self.next_window_start = end - Duration::seconds(10);
+        let mut cut_short = false;
+        if let Some(max_window) = self.max_window.as_mut() {
+            // Windows overlap, so they have to be longer than the overlap to make any progress
+            *max_window = (*max_window).max(self.overlap + Duration::seconds(MIN_WINDOW));
+            if end - start > *max_window {
+                end = start + *max_window;
+                cut_short = true;
+                info!("Catching up, fetching {} to {}", start, end);
+            }
+        }
+        self.last_window = Some((end - start, cut_short));
+
+        // Use time windows that overlap to avoid missing events that were indexed late
+        self.next_window_start = end - self.overlap;
 
         Some((start, end))
     }
 }
 
 impl Snapshot {
-    pub fn new(from: DateTime<Utc>, window: u64) -> Self {
-        let start = from;
-        let end = from + Duration::seconds(window as i64);
+    /// A snapshot of the logs between `from` and `to`
+    pub fn new(from: DateTime<Utc>, to: DateTime<Utc>) -> Self {
         Self {
-            next_window_start: start,
-            next_window_end: Some(end),
+            next_window_start: from,
+            next_window_end: Some(to),
         }
     }
+
+    /// Split the window between `from` and `to` into `shards` consecutive snapshots, which can
+    /// be fetched concurrently. Returns each snapshot along with its start and end
+    pub fn shards(
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        shards: usize,
+    ) -> Vec<(DateTime<Utc>, DateTime<Utc>, Snapshot)> {
+        let window_ms = (to - from).num_milliseconds().max(1);
+        let shards = shards.clamp(1, window_ms as usize) as i64;
+        (0..shards)
+            .map(|i| {
+                let start = from + Duration::milliseconds(window_ms * i / shards);
+                let end = from + Duration::milliseconds(window_ms * (i + 1) / shards);
+                (start, end, Snapshot::new(start, end))
+            })
+            .collect()
+    }
 }
 
+impl WindowMode for Snapshot {}
+
 impl Iterator for Snapshot {
     type Item = (DateTime<Utc>, DateTime<Utc>);
 
@@ -209,3 +578,266 @@ impl Iterator for Snapshot {
         Some((start, end))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> LogSource<Snapshot> {
+        let now = Utc::now();
+        LogSource::new(
+            "example.com".to_string(),
+            "*".to_string(),
+            Snapshot::new(now, now),
+        )
+    }
+
+    #[test]
+    fn reads_log_events() {
+        let raw = json!({
+            "id": "a",
+            "query": "api",
+            "attributes": {
+                "timestamp": "2024-01-01T10:00:00Z",
+                "status": "error",
+                "service": "api",
+                "message": "boom",
+                "tags": {"team": ["a", "b"], "env": "prod", "canary": null},
+                "raw_tags": ["team:a", "team:b", "env:prod", "canary"],
+                "attributes": {"duration": 12}
+            }
+        });
+        let event = LogEvent::from_json(raw.clone()).unwrap();
+        assert_eq!(event.id.as_deref(), Some("a"));
+        assert_eq!(event.query.as_deref(), Some("api"));
+        assert_eq!(
+            event.timestamp,
+            time::parse_timestamp(&json!("2024-01-01T10:00:00Z"))
+        );
+        assert_eq!(event.status.as_deref(), Some("error"));
+        assert_eq!(event.host, None);
+        assert_eq!(event.tags["team"], ["a", "b"]);
+        assert_eq!(event.tags["env"], ["prod"]);
+        assert!(event.tags["canary"].is_empty());
+        assert_eq!(event.raw_tags.len(), 4);
+        assert_eq!(event.attributes["duration"], 12);
+        assert_eq!(event.raw(), &raw);
+
+        assert!(LogEvent::from_json(json!("not an event")).is_err());
+    }
+
+    #[test]
+    fn skips_non_objects_and_repeated_events() {
+        let mut source = source();
+        let body = json!({"data": [
+            {"id": "a", "attributes": {}},
+            "not an event",
+            {"id": "a", "attributes": {}},
+            {"attributes": {"message": "no id"}},
+            {"attributes": {"message": "no id"}},
+            {"attributes": {"message": "another"}}
+        ]});
+        let events = source.extract_results(body).unwrap();
+        assert_eq!(events.len(), 3);
+
+        // Events seen in an earlier page aren't returned again
+        let body = json!({"data": [{"id": "a"}, {"id": "b"}]});
+        let events = source.extract_results(body).unwrap();
+        assert_eq!(events, [json!({"id": "b"})]);
+    }
+
+    fn pages(pages: usize) -> WindowStats {
+        WindowStats {
+            pages,
+            ..Default::default()
+        }
+    }
+
+    fn unpacked(tags: &[&str]) -> Value {
+        unpack_tags(json!({"attributes": {"tags": tags}}))["attributes"].take()
+    }
+
+    #[test]
+    fn keeps_everything_after_the_first_colon() {
+        let attributes = unpacked(&["url:http://x:80/a", "env:prod"]);
+        assert_eq!(attributes["tags"]["url"], "http://x:80/a");
+        assert_eq!(attributes["tags"]["env"], "prod");
+    }
+
+    #[test]
+    fn collects_repeated_keys() {
+        let attributes = unpacked(&["team:a", "team:b", "team:a", "env:prod", "env:prod"]);
+        assert_eq!(attributes["tags"]["team"], json!(["a", "b"]));
+        // The same tag twice is still just one value
+        assert_eq!(attributes["tags"]["env"], "prod");
+    }
+
+    #[test]
+    fn unpacks_valueless_tags_as_null() {
+        let attributes = unpacked(&["canary", "env:"]);
+        assert_eq!(attributes["tags"]["canary"], Value::Null);
+        assert!(attributes["tags"]
+            .as_object()
+            .unwrap()
+            .contains_key("canary"));
+        assert_eq!(attributes["tags"]["env"], "");
+    }
+
+    #[test]
+    fn keeps_the_raw_tags() {
+        let tags = ["team:a", "team:b", "canary"];
+        assert_eq!(unpacked(&tags)["raw_tags"], json!(tags));
+        // Events without a list of tags are left alone
+        let event = json!({"attributes": {"message": "m"}});
+        assert_eq!(unpack_tags(event.clone()), event);
+    }
+
+    #[test]
+    fn sizes_backfill_windows_by_pages() {
+        let mut follow = Follow::since(Utc::now() - Duration::days(1), Duration::hours(1));
+        let span = |(start, end): (DateTime<Utc>, DateTime<Utc>)| end - start;
+
+        assert_eq!(span(follow.next().unwrap()), Duration::hours(1));
+        assert!(!follow.caught_up());
+        follow.record(&pages(TARGET_PAGES + 1));
+        assert_eq!(span(follow.next().unwrap()), Duration::minutes(30));
+        follow.record(&pages(3));
+        assert_eq!(span(follow.next().unwrap()), Duration::minutes(30));
+        follow.record(&pages(1));
+        assert_eq!(span(follow.next().unwrap()), Duration::hours(1));
+        follow.record(&pages(1));
+        assert_eq!(span(follow.next().unwrap()), Duration::hours(2));
+    }
+
+    #[test]
+    fn adapts_windows_to_pages() {
+        let span = |(start, end): (DateTime<Utc>, DateTime<Utc>)| end - start;
+        let mut follow =
+            Follow::since(Utc::now() - Duration::days(1), Duration::hours(1)).adaptive();
+
+        // Shrinks busy windows, down to a floor past the overlap
+        assert_eq!(span(follow.next().unwrap()), Duration::hours(1));
+        follow.record(&pages(TARGET_PAGES + 1));
+        assert_eq!(span(follow.next().unwrap()), Duration::minutes(30));
+        for _ in 0..20 {
+            follow.record(&pages(TARGET_PAGES + 1));
+            follow.next();
+        }
+        let floor = Duration::seconds(10 + MIN_WINDOW);
+        assert_eq!(span(follow.next().unwrap()), floor);
+
+        // And grows quiet ones back
+        follow.record(&pages(1));
+        assert_eq!(span(follow.next().unwrap()), floor * 2);
+        follow.record(&pages(1));
+        assert_eq!(span(follow.next().unwrap()), floor * 4);
+    }
+
+    #[test]
+    fn stops_limiting_windows_once_they_reach_now() {
+        let mut follow = Follow::new(3600).adaptive();
+        follow.next();
+        // Even following, a busy window shrinks the next ones
+        follow.record(&pages(TARGET_PAGES + 1));
+        assert_eq!(follow.max_window.map(|w| w.num_minutes()), Some(30));
+        follow.next();
+        assert!(follow.caught_up());
+        follow.record(&pages(1));
+        assert_eq!(follow.max_window, None);
+    }
+
+    #[test]
+    fn adapts_the_overlap_to_lag() {
+        let lag = |seconds| WindowStats {
+            pages: 1,
+            max_lag: Some(Duration::seconds(seconds)),
+            ..Default::default()
+        };
+        let mut follow = Follow::new(60).adaptive();
+        follow.next();
+
+        follow.record(&lag(20));
+        assert_eq!(follow.overlap, Duration::seconds(30));
+        // Lag the overlap already covers changes nothing
+        follow.record(&lag(5));
+        assert_eq!(follow.overlap, Duration::seconds(30));
+        follow.record(&lag(1000));
+        assert_eq!(follow.overlap, Duration::seconds(MAX_OVERLAP));
+
+        // Without late events, it shrinks back to the configured overlap
+        follow.record(&pages(1));
+        assert_eq!(follow.overlap, Duration::seconds(270));
+        for _ in 0..100 {
+            follow.record(&pages(1));
+        }
+        assert_eq!(follow.overlap, Duration::seconds(10));
+    }
+
+    #[test]
+    fn defers_rescans_until_caught_up() {
+        let start = Utc::now() - Duration::hours(1);
+        let mut follow = Follow::since(start, Duration::minutes(10))
+            .with_rescan(Duration::minutes(5), Duration::zero());
+
+        let mut last_end = start;
+        while !follow.caught_up() || last_end == start {
+            let (_, end) = follow.next().unwrap();
+            assert!(!follow.revisiting(), "Rescanned during the backfill");
+            assert!(end > last_end);
+            last_end = end;
+            follow.record(&pages(3));
+        }
+
+        let (rescan_start, rescan_end) = follow.next().unwrap();
+        assert!(follow.revisiting());
+        assert_eq!(rescan_end - rescan_start, Duration::minutes(5));
+        follow.record(&pages(3));
+        follow.rescan.as_mut().unwrap().every = Duration::hours(1);
+        let (next_start, _) = follow.next().unwrap();
+        assert!(!follow.revisiting());
+        assert_eq!(next_start, last_end - Duration::seconds(10));
+    }
+
+    #[test]
+    fn measures_lag_between_windows_in_sequence() {
+        let follow = Follow::new(60).with_rescan(Duration::minutes(5), Duration::zero());
+        let mut source = LogSource::new("example.com".to_string(), "*".to_string(), follow);
+
+        source.construct_query().unwrap();
+        let first_end = source.last_window_end.unwrap();
+        // The rescan isn't measured, and doesn't count as the end of the last window
+        source.construct_query().unwrap();
+        assert_eq!(source.previous_window_end, None);
+        assert_eq!(source.last_window_end, Some(first_end));
+        source.mode.rescan.as_mut().unwrap().every = Duration::hours(1);
+        source.construct_query().unwrap();
+        assert_eq!(source.previous_window_end, Some(first_end));
+    }
+
+    // Steps a backfill until it's caught up, checking every window moves forward
+    fn backfill(mut follow: Follow, pages_per_window: usize) {
+        let (mut last_start, mut last_end) = follow.next().unwrap();
+        for _ in 0..1000 {
+            if follow.caught_up() {
+                return;
+            }
+            follow.record(&pages(pages_per_window));
+            let (start, end) = follow.next().unwrap();
+            assert!(start > last_start, "{} is before {}", start, last_start);
+            assert!(end > last_end, "{} is before {}", end, last_end);
+            (last_start, last_end) = (start, end);
+        }
+        panic!("Backfill never caught up");
+    }
+
+    #[test]
+    fn backfill_windows_always_move_forward() {
+        let start = Utc::now() - Duration::hours(1);
+        backfill(Follow::since(start, Duration::seconds(5)), 3);
+        backfill(Follow::since(start, Duration::zero()), 1);
+        backfill(
+            Follow::since(start, Duration::minutes(1)).with_overlap(Duration::minutes(2)),
+            3,
+        );
+    }
+}
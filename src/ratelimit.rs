@@ -0,0 +1,425 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use fs2::FileExt;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{transport::Response, Error};
+
+/// Shares the API rate limit between everything making requests with the same keys, whether
+/// that's several tailers in one process, or several dogtail processes. Each request takes one
+/// from the budget before it's sent, and once it's spent everyone waits for the period to
+/// reset. The budget is corrected from the rate-limit headers of every response.
+#[async_trait]
+pub trait RateLimitCoordinator: Send + Sync {
+    /// Wait until there's budget for another request, and take it
+    async fn acquire(&self);
+
+    /// Correct the budget from the rate-limit headers of a response
+    async fn update(&self, status: &RateLimitStatus);
+}
+
+/// The rate-limit headers of a response, and when we next want to make a request
+#[derive(Debug)]
+pub struct RateLimitStatus {
+    pub(crate) period: Duration,
+    pub(crate) limit: Option<u32>,
+    pub(crate) remaining_budget: u32,
+    pub(crate) next_request_allowed: Instant,
+}
+
+/// A budget shared between tailers in this process.
+///
+/// Requests are granted in the order they were asked for, and since a tailer only has one
+/// request waiting at a time, tailers contending for the budget take turns rather than the
+/// busiest one starving the rest.
+#[derive(Clone, Default)]
+pub struct RateLimitBudget {
+    state: Arc<Mutex<BudgetState>>,
+    queue: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// A budget shared between dogtail processes on this machine, through a state file that each
+/// takes an exclusive lock on while it reads and updates it. Within a process, requests are
+/// granted in order as for [RateLimitBudget]. Between processes, whoever gets the lock first
+/// after a reset goes first.
+#[derive(Clone)]
+pub struct LockFileBudget {
+    path: PathBuf,
+    queue: Arc<tokio::sync::Mutex<()>>,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    limit: Option<u32>,
+    remaining: Option<u32>, // None until we've heard from the API
+    reset_at: Option<SystemTime>,
+}
+
+impl RateLimitBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RateLimitCoordinator for RateLimitBudget {
+    async fn acquire(&self) {
+        let _turn = self.queue.lock().await;
+        loop {
+            let Some(wait) = self.state.lock().unwrap().take() else {
+                return;
+            };
+            pause_for(wait).await;
+        }
+    }
+
+    async fn update(&self, status: &RateLimitStatus) {
+        self.state.lock().unwrap().update(status);
+    }
+}
+
+impl LockFileBudget {
+    /// Share the budget through the file at `path`, which is created if it doesn't exist. Every
+    /// process using the same API keys should be pointed at the same file
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            queue: Default::default(),
+        }
+    }
+
+    /// Lock the state file, and run `f` on its contents, writing them back afterwards
+    async fn with_state<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut BudgetState) -> T + Send + 'static,
+    ) -> Result<T, std::io::Error> {
+        let path = self.path.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.lock_exclusive()?;
+            let mut buf = String::new();
+            file.read_to_string(&mut buf)?;
+            let mut state = serde_json::from_str(&buf)
+                .map(|v| BudgetState::from_json(&v))
+                .unwrap_or_default();
+            let res = f(&mut state);
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(state.to_json().to_string().as_bytes())?;
+            FileExt::unlock(&file)?;
+            Ok(res)
+        })
+        .await
+        .expect("Rate limit file task panicked")
+    }
+}
+
+#[async_trait]
+impl RateLimitCoordinator for LockFileBudget {
+    async fn acquire(&self) {
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = match self.with_state(|state| state.take()).await {
+                Ok(Some(wait)) => wait,
+                Ok(None) => return,
+                Err(e) => {
+                    // Better to risk a 429 than to stop tailing altogether
+                    debug!("Couldn't read rate limit file {:?}: {}", self.path, e);
+                    return;
+                }
+            };
+            pause_for(wait).await;
+        }
+    }
+
+    async fn update(&self, status: &RateLimitStatus) {
+        let (limit, remaining, reset_at, period) = (
+            status.limit,
+            status.remaining_budget,
+            status.reset_at(),
+            status.period,
+        );
+        let res = self
+            .with_state(move |state| state.apply(limit, remaining, reset_at, period))
+            .await;
+        if let Err(e) = res {
+            debug!("Couldn't update rate limit file {:?}: {}", self.path, e);
+        }
+    }
+}
+
+// Same as for a single tailer, jitter so we don't all pile in at once
+async fn pause_for(wait: Duration) {
+    let jitter = Duration::from_secs_f32(rand::random::<f32>() * 5.0);
+    debug!(
+        "Shared rate limit budget spent, waiting {}s",
+        (wait + jitter).as_secs()
+    );
+    tokio::time::sleep(wait + jitter).await;
+}
+
+impl BudgetState {
+    /// Take a request from the budget, or return how long until the budget resets if it's spent
+    fn take(&mut self) -> Option<Duration> {
+        let now = SystemTime::now();
+        if let Some(reset_at) = self.reset_at.filter(|r| *r <= now) {
+            debug!("Shared rate limit period reset at {:?}", reset_at);
+            self.remaining = self.limit;
+            self.reset_at = None;
+        }
+        match (self.remaining, self.reset_at) {
+            (Some(0), Some(reset_at)) => Some(reset_at.duration_since(now).unwrap_or_default()),
+            (Some(remaining), _) => {
+                self.remaining = Some(remaining.saturating_sub(1));
+                None
+            }
+            (None, _) => None,
+        }
+    }
+
+    fn update(&mut self, status: &RateLimitStatus) {
+        self.apply(
+            status.limit,
+            status.remaining_budget,
+            status.reset_at(),
+            status.period,
+        )
+    }
+
+    fn apply(
+        &mut self,
+        limit: Option<u32>,
+        remaining: u32,
+        reset_at: SystemTime,
+        period: Duration,
+    ) {
+        self.limit = limit.or(self.limit);
+        // Responses to requests made concurrently can arrive in any order, so within a period
+        // trust whichever has the least budget left
+        let new_period = self.reset_at.is_none_or(|r| reset_at > r + period / 2);
+        self.remaining = match self.remaining {
+            Some(r) if !new_period => Some(r.min(remaining)),
+            _ => Some(remaining),
+        };
+        self.reset_at = Some(reset_at);
+    }
+
+    fn from_json(v: &Value) -> Self {
+        let get = |key: &str| v[key].as_u64();
+        BudgetState {
+            limit: get("limit").map(|l| l as u32),
+            remaining: get("remaining").map(|r| r as u32),
+            reset_at: get("reset_at_ms").map(|ms| UNIX_EPOCH + Duration::from_millis(ms)),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let reset_at_ms = self
+            .reset_at
+            .and_then(|r| r.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64);
+        json!({
+            "limit": self.limit,
+            "remaining": self.remaining,
+            "reset_at_ms": reset_at_ms,
+        })
+    }
+}
+
+impl TryFrom<&Response> for RateLimitStatus {
+    type Error = Error;
+
+    fn try_from(response: &Response) -> Result<Self, Error> {
+        // TODO - figure out a use for this in the wait time calculation. For now it's only
+        // used to refill a shared budget
+        let limit = response
+            .headers
+            .get("x-ratelimit-limit")
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse().ok());
+        let period = Duration::from_secs(header(response, "x-ratelimit-period")?);
+        let remaining_budget = header(response, "x-ratelimit-remaining")?;
+        let reset_time =
+            Instant::now() + Duration::from_secs(header(response, "x-ratelimit-reset")?);
+
+        let status = RateLimitStatus {
+            period,
+            limit,
+            remaining_budget,
+            next_request_allowed: reset_time,
+        };
+        debug!("Rate limit status: {:?}", status);
+        Ok(status)
+    }
+}
+
+fn header<T: std::str::FromStr>(response: &Response, key: &str) -> Result<T, Error> {
+    response
+        .headers
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| Error::malformed(format!("Header {} not found", key)))
+}
+
+// In order to be a good citizen, we always wait until reset + [0.0..5.0) seconds before requesting again
+// TODO - this is an antipattern - it should be impossible to make another request until the rate limit is reset
+impl RateLimitStatus {
+    /// How many requests the API will allow in each period, if it told us
+    pub fn limit(&self) -> Option<u32> {
+        self.limit
+    }
+
+    /// How many requests are left in the current period
+    pub fn remaining(&self) -> u32 {
+        self.remaining_budget
+    }
+
+    /// How long each rate-limit period lasts
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// When we're next allowed to make a request, in wall-clock time so it can be shared
+    /// between processes
+    pub fn reset_at(&self) -> SystemTime {
+        SystemTime::now() + self.next_request_allowed.duration_since(Instant::now())
+    }
+
+    /// A status that holds off requests for `wait`, for when a response didn't have any
+    /// rate-limit headers to go on
+    pub(crate) fn wait(wait: Duration) -> Self {
+        RateLimitStatus {
+            period: wait,
+            limit: None,
+            remaining_budget: 0,
+            next_request_allowed: Instant::now() + wait,
+        }
+    }
+
+    pub(crate) async fn pause(&self) {
+        let wait = self.next_request_allowed.duration_since(Instant::now());
+        let jitter = Duration::from_secs_f32(rand::random::<f32>() * 5.0);
+        let wait = wait + jitter;
+        if wait > Duration::from_secs(0) {
+            debug!("Waiting {}s", wait.as_secs());
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // We scale how long we wait by portion of time period until next budget allocation and
+    // by how likely we are to get useful results from our query (basically, the last time we
+    // hit the server, how many useful result did we get).
+    pub(crate) fn scale_remaining_by(&mut self, returned: usize, limit: usize) {
+        let useful_results_factor = returned as f32 / limit as f32;
+        // The more useful results we got, the less of our budget period we want to wait
+        let desired_wait = self.period.as_millis() as f32 * (1.0 - useful_results_factor);
+
+        if self.remaining_budget > 0 {
+            let wait = desired_wait
+                .min(
+                    self.next_request_allowed
+                        .duration_since(Instant::now())
+                        .as_millis() as f32,
+                )
+                .max(0.0);
+            debug!(
+                "Scaled wait by {}, desired wait was {}ms, waiting {}ms",
+                useful_results_factor, desired_wait, wait
+            );
+            self.next_request_allowed = Instant::now() + Duration::from_millis(wait as u64);
+        } else {
+            debug!("No remaining budget, not scaling wait time");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: Duration = Duration::from_secs(60);
+
+    fn state(remaining: u32, reset_in: Duration) -> BudgetState {
+        BudgetState {
+            limit: Some(10),
+            remaining: Some(remaining),
+            reset_at: Some(SystemTime::now() + reset_in),
+        }
+    }
+
+    #[test]
+    fn takes_from_the_budget_until_its_spent() {
+        // Until we've heard from the API, there's nothing to go on
+        assert_eq!(BudgetState::default().take(), None);
+
+        let mut state = state(2, PERIOD);
+        assert_eq!(state.take(), None);
+        assert_eq!(state.take(), None);
+        assert_eq!(state.remaining, Some(0));
+        let wait = state.take().unwrap();
+        assert!(wait > PERIOD - Duration::from_secs(1) && wait <= PERIOD);
+        assert_eq!(state.remaining, Some(0));
+    }
+
+    #[test]
+    fn refills_the_budget_once_the_period_resets() {
+        let mut state = state(0, Duration::ZERO);
+        state.reset_at = Some(SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(state.take(), None);
+        assert_eq!(state.remaining, Some(9));
+        assert_eq!(state.reset_at, None);
+    }
+
+    #[test]
+    fn trusts_the_lowest_budget_within_a_period() {
+        let mut state = state(5, PERIOD);
+        let reset_at = state.reset_at.unwrap();
+
+        // A response to an earlier request, arriving late
+        state.apply(None, 8, reset_at + Duration::from_secs(1), PERIOD);
+        assert_eq!(state.remaining, Some(5));
+        assert_eq!(state.limit, Some(10));
+        state.apply(Some(20), 3, reset_at, PERIOD);
+        assert_eq!(state.remaining, Some(3));
+        assert_eq!(state.limit, Some(20));
+
+        // A new period starts with whatever it says is left
+        state.apply(None, 19, reset_at + PERIOD, PERIOD);
+        assert_eq!(state.remaining, Some(19));
+        assert_eq!(state.reset_at, Some(reset_at + PERIOD));
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let state = state(4, PERIOD);
+        let read = BudgetState::from_json(&state.to_json());
+        assert_eq!(read.limit, state.limit);
+        assert_eq!(read.remaining, state.remaining);
+        let drift = state
+            .reset_at
+            .unwrap()
+            .duration_since(read.reset_at.unwrap())
+            .unwrap();
+        assert!(drift < Duration::from_millis(1));
+
+        let read = BudgetState::from_json(&BudgetState::default().to_json());
+        assert_eq!(
+            (read.limit, read.remaining, read.reset_at),
+            (None, None, None)
+        );
+    }
+}
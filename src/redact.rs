@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use regex::{Match, Regex};
+use serde_json::Value;
+
+use crate::JsonKey;
+
+/// Blanks out sensitive data, like emails, tokens and card numbers, in every string of an
+/// event, and replaces the values of denylisted fields entirely. Each redaction is replaced
+/// with a marker naming what was found, e.g. "[REDACTED:email]", and counted, so a run can
+/// report what it hid
+pub struct Redactor {
+    detectors: Vec<Detector>,
+    keys: Vec<JsonKey>,
+    counts: Mutex<BTreeMap<String, usize>>,
+}
+
+struct Detector {
+    name: String,
+    regex: Regex,
+    /// Checks a match really is what we're looking for, e.g. that a number passes the Luhn
+    /// check, given the whole string it was found in
+    validate: Validate,
+}
+
+type Validate = fn(&str, &Match) -> bool;
+
+/// How many of each kind of thing a [Redactor] has redacted
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub counts: BTreeMap<String, usize>,
+}
+
+impl Redactor {
+    /// A redactor that doesn't redact anything, until detectors or keys are added
+    pub fn new() -> Self {
+        Redactor {
+            detectors: vec![],
+            keys: vec![],
+            counts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Redact emails, JWTs, bearer tokens, card numbers (that pass the Luhn check), and IPv4 and
+    /// IPv6 addresses. Only strings are looked at, so numbers, e.g. a card number logged as one,
+    /// are left as they are
+    pub fn with_builtin_detectors(mut self) -> Self {
+        let builtin: [(&str, &str, Validate); 6] = [
+            (
+                "jwt",
+                r"eyJ[A-Za-z0-9_-]+\.eyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]*",
+                |_, _| true,
+            ),
+            (
+                "bearer",
+                r"(?i)\bbearer\s+([A-Za-z0-9._~+/-]+=*)",
+                |_, _| true,
+            ),
+            (
+                "email",
+                r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}",
+                |_, _| true,
+            ),
+            ("card", r"\b\d(?:[ -]?\d){12,15}\b", |_, m| {
+                is_card(m.as_str())
+            }),
+            ("ip", r"\b\d{1,3}(?:\.\d{1,3}){3}\b", |_, m| {
+                m.as_str().parse::<Ipv4Addr>().is_ok()
+            }),
+            (
+                "ip",
+                r"(?:[0-9A-Fa-f]{1,4}|:)(?::[0-9A-Fa-f]{0,4}){1,7}",
+                is_ipv6,
+            ),
+        ];
+        for (name, regex, validate) in builtin {
+            self.detectors.push(Detector {
+                name: name.to_string(),
+                regex: Regex::new(regex).expect("Built-in redaction regexes are valid"),
+                validate,
+            });
+        }
+        self
+    }
+
+    /// Redact whatever matches `regex`, counting it under `name`. If the regex has a group, only
+    /// what the first group matched is redacted, e.g. the password in "password=(\S+)"
+    pub fn with_pattern(mut self, name: String, regex: Regex) -> Self {
+        self.detectors.push(Detector {
+            name,
+            regex,
+            validate: |_, _| true,
+        });
+        self
+    }
+
+    /// Replace the whole value at `key`, whatever it is
+    pub fn with_key(mut self, key: JsonKey) -> Self {
+        self.keys.push(key);
+        self
+    }
+
+    pub fn redact(&self, event: &mut Value) {
+        let mut found = BTreeMap::new();
+        for key in &self.keys {
+            if key.get(event).is_some() && key.set(event, Value::String("[REDACTED]".to_string())) {
+                *found.entry(key.to_string()).or_default() += 1;
+            }
+        }
+        self.redact_value(event, &mut found);
+
+        if !found.is_empty() {
+            let mut counts = self.counts.lock().unwrap();
+            for (name, count) in found {
+                *counts.entry(name).or_default() += count;
+            }
+        }
+    }
+
+    fn redact_value(&self, value: &mut Value, found: &mut BTreeMap<String, usize>) {
+        match value {
+            Value::String(s) => {
+                for detector in &self.detectors {
+                    if let Some(redacted) = detector.redact(s, found) {
+                        *s = redacted;
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.redact_value(value, found);
+                }
+            }
+            Value::Object(fields) => {
+                for value in fields.values_mut() {
+                    self.redact_value(value, found);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// What's been redacted so far
+    pub fn report(&self) -> Report {
+        Report {
+            counts: self.counts.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Detector {
+    /// The string with everything this detects replaced, or None if there was nothing to replace
+    fn redact(&self, s: &str, found: &mut BTreeMap<String, usize>) -> Option<String> {
+        let mut redacted = String::new();
+        let mut last = 0;
+        for captures in self.regex.captures_iter(s) {
+            let Some(m) = captures.get(1).or_else(|| captures.get(0)) else {
+                continue;
+            };
+            if m.as_str().is_empty() || !(self.validate)(s, &m) {
+                continue;
+            }
+            redacted.push_str(&s[last..m.start()]);
+            redacted.push_str(&format!("[REDACTED:{}]", self.name));
+            last = m.end();
+            *found.entry(self.name.clone()).or_default() += 1;
+        }
+        if last == 0 {
+            return None;
+        }
+        redacted.push_str(&s[last..]);
+        Some(redacted)
+    }
+}
+
+// Only 13 to 16 digit numbers starting with 2-6 are taken for cards, which covers the major
+// networks, while leaving alone ids like trace ids, which are longer, a tenth of which would
+// pass the Luhn check by chance
+fn is_card(candidate: &str) -> bool {
+    let digits: Vec<u32> = candidate.chars().filter_map(|c| c.to_digit(10)).collect();
+    if !(13..=16).contains(&digits.len()) || !(2..=6).contains(&digits[0]) {
+        return false;
+    }
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, d)| match (i % 2, d * 2) {
+            (0, _) => *d,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+// The candidate regex is loose, so it's checked by actually parsing it, and it mustn't be part
+// of a longer word, so e.g. "std::fmt" isn't taken for the address "d::f". Plenty of text, like
+// paths such as "add::cafe", also parses as a two group address, so at least three groups are
+// needed, which leaves short addresses like "::1" alone
+fn is_ipv6(s: &str, m: &Match) -> bool {
+    let part_of_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let before = s[..m.start()].chars().next_back();
+    let after = s[m.end()..].chars().next();
+    let groups = m.as_str().split(':').filter(|g| !g.is_empty()).count();
+    !before.is_some_and(part_of_word)
+        && !after.is_some_and(part_of_word)
+        && groups >= 3
+        && m.as_str().parse::<Ipv6Addr>().is_ok()
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: usize = self.counts.values().sum();
+        if total == 0 {
+            return write!(f, "Redacted nothing");
+        }
+        let counts: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(name, count)| format!("{} {}", count, name))
+            .collect();
+        write!(f, "Redacted {} values: {}", total, counts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn redact(redactor: &Redactor, s: &str) -> String {
+        let mut value = Value::String(s.to_string());
+        redactor.redact(&mut value);
+        value.as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn redacts_builtin_detections() {
+        let redactor = Redactor::new().with_builtin_detectors();
+        let cases = [
+            ("mail jo.b+x@example.co.uk now", "mail [REDACTED:email] now"),
+            ("card 4111 1111 1111 1111", "card [REDACTED:card]"),
+            ("card 5500-0000-0000-0004.", "card [REDACTED:card]."),
+            ("from 10.0.0.1:443", "from [REDACTED:ip]:443"),
+            ("from 2001:db8::ff00:42:8329", "from [REDACTED:ip]"),
+            ("from fe80::1ff:fe23:4567:890a", "from [REDACTED:ip]"),
+            (
+                "token eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiIxIn0.sig_-x",
+                "token [REDACTED:jwt]",
+            ),
+            (
+                "Authorization: Bearer abc.def/ghi=",
+                "Authorization: Bearer [REDACTED:bearer]",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(redact(&redactor, input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn leaves_near_misses_alone() {
+        let redactor = Redactor::new().with_builtin_detectors();
+        let cases = [
+            "2024-01-01T10:00:00.123Z",
+            "at 10:00:00",
+            "id 123e4567-e89b-12d3-a456-426614174000",
+            "mac 00:1a:2b:3c:4d:5e",
+            "use std::fmt::Display",
+            "a::b",
+            "dead::beef here",
+            "call add::cafe",
+            "loopback ::1",
+            "trace 1234567890123456789",
+            "card 4111 1111 1111 1112",
+            "version 1.2.3.4000",
+            "user@localhost",
+        ];
+        for input in cases {
+            assert_eq!(redact(&redactor, input), input);
+        }
+        assert_eq!(redactor.report().counts.len(), 0);
+    }
+
+    #[test]
+    fn redacts_only_the_group_of_custom_patterns() {
+        let redactor = Redactor::new()
+            .with_pattern(
+                "password".to_string(),
+                Regex::new(r"password=(\S+)").unwrap(),
+            )
+            .with_pattern("ticket".to_string(), Regex::new(r"T-\d+").unwrap());
+        assert_eq!(
+            redact(&redactor, "password=hunter2 for T-12"),
+            "password=[REDACTED:password] for [REDACTED:ticket]"
+        );
+    }
+
+    #[test]
+    fn replaces_denylisted_keys() {
+        let redactor = Redactor::new()
+            .with_key(JsonKey::from("attributes.token"))
+            .with_key(JsonKey::from("attributes.missing"));
+        let mut event = json!({"attributes": {"token": {"nested": 1}, "message": "m"}});
+        redactor.redact(&mut event);
+        assert_eq!(
+            event,
+            json!({"attributes": {"token": "[REDACTED]", "message": "m"}})
+        );
+        assert_eq!(redactor.report().counts["attributes.token"], 1);
+        assert!(!redactor.report().counts.contains_key("attributes.missing"));
+    }
+
+    #[test]
+    fn reports_counts_across_events() {
+        let redactor = Redactor::new()
+            .with_builtin_detectors()
+            .with_key(JsonKey::from("secret"));
+        assert_eq!(redactor.report().to_string(), "Redacted nothing");
+
+        let mut event = json!({"secret": 1, "tags": ["a@b.io", "c@d.io 10.0.0.1"]});
+        redactor.redact(&mut event);
+        let mut event = json!({"message": "e@f.io"});
+        redactor.redact(&mut event);
+
+        let report = redactor.report();
+        assert_eq!(report.counts["email"], 3);
+        assert_eq!(report.counts["ip"], 1);
+        assert_eq!(
+            report.to_string(),
+            "Redacted 5 values: 3 email, 1 ip, 1 secret"
+        );
+    }
+}
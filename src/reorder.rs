@@ -0,0 +1,214 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::{time, JsonKey};
+
+/// Holds events back for a fixed lag, and hands them out sorted by timestamp. Follow windows
+/// overlap, and pages can come back slightly out of order, so this sits between a
+/// [crate::tailer::Tailer] and a [crate::sink::ConsumerPool] when the output needs to be monotonic.
+///
+/// An event is released once an event at least `lag` newer than it has been seen, or when
+/// the buffer is drained. Events that turn up after something newer has already been released
+/// can't be put back in order, so they're passed straight through.
+pub struct ReorderBuffer {
+    lag: Duration,
+    key: JsonKey,
+    pending: BinaryHeap<Reverse<Pending>>,
+    newest_seen: Option<DateTime<Utc>>,
+    last_released: Option<DateTime<Utc>>,
+    received: u64,
+}
+
+struct Pending {
+    timestamp: DateTime<Utc>,
+    seq: u64, // Keeps events with the same timestamp in arrival order
+    event: Value,
+}
+
+impl ReorderBuffer {
+    /// Construct a buffer ordering by `attributes.timestamp`
+    pub fn new(lag: Duration) -> Self {
+        Self::with_key(lag, JsonKey::from("attributes.timestamp"))
+    }
+
+    /// Construct a buffer ordering by the rfc3339 timestamp found at `key`
+    pub fn with_key(lag: Duration, key: JsonKey) -> Self {
+        ReorderBuffer {
+            lag,
+            key,
+            pending: BinaryHeap::new(),
+            newest_seen: None,
+            last_released: None,
+            received: 0,
+        }
+    }
+
+    /// Add an event to the buffer, returning any events that are now ready to be emitted, in order
+    pub fn push(&mut self, event: Value) -> Vec<Value> {
+        let Some(timestamp) = self.timestamp(&event) else {
+            // Nothing to order by, so there's no point holding on to it
+            return vec![event];
+        };
+
+        if self.last_released.is_some_and(|l| timestamp < l) {
+            warn!(
+                "Event at {} arrived after events up to {} were emitted, consider a longer lag",
+                timestamp,
+                self.last_released.unwrap()
+            );
+            return vec![event];
+        }
+
+        self.received += 1;
+        self.pending.push(Reverse(Pending {
+            timestamp,
+            seq: self.received,
+            event,
+        }));
+        self.newest_seen = self.newest_seen.max(Some(timestamp));
+
+        let watermark = self.newest_seen.unwrap() - self.lag;
+        self.release_until(Some(watermark))
+    }
+
+    /// Emit everything still held, in order
+    pub fn drain(&mut self) -> Vec<Value> {
+        self.release_until(None)
+    }
+
+    /// The number of events currently being held
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn release_until(&mut self, watermark: Option<DateTime<Utc>>) -> Vec<Value> {
+        let mut ready = Vec::new();
+        while let Some(Reverse(next)) = self.pending.peek() {
+            if watermark.is_some_and(|w| next.timestamp > w) {
+                break;
+            }
+            let Reverse(next) = self.pending.pop().unwrap();
+            self.last_released = Some(next.timestamp);
+            ready.push(next.event);
+        }
+        ready
+    }
+
+    fn timestamp(&self, event: &Value) -> Option<DateTime<Utc>> {
+        time::parse_timestamp(&self.key.get(event)?)
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.timestamp, self.seq).cmp(&(other.timestamp, other.seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(id: &str, timestamp: Option<&str>) -> Value {
+        match timestamp {
+            Some(t) => json!({"id": id, "attributes": {"timestamp": t}}),
+            None => json!({"id": id, "attributes": {}}),
+        }
+    }
+
+    fn ids(events: &[Value]) -> Vec<&str> {
+        events.iter().map(|e| e["id"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn releases_events_once_the_watermark_passes_them() {
+        let mut buffer = ReorderBuffer::new(Duration::seconds(10));
+        assert!(buffer
+            .push(event("a", Some("2024-01-01T10:00:05Z")))
+            .is_empty());
+        assert!(buffer
+            .push(event("b", Some("2024-01-01T10:00:00Z")))
+            .is_empty());
+        // Still within the lag of the newest event
+        assert!(buffer
+            .push(event("c", Some("2024-01-01T10:00:09Z")))
+            .is_empty());
+        assert_eq!(buffer.len(), 3);
+
+        // Exactly at the watermark is released
+        let ready = buffer.push(event("d", Some("2024-01-01T10:00:15Z")));
+        assert_eq!(ids(&ready), ["b", "a"]);
+        assert_eq!(buffer.len(), 2);
+    }
+
+    #[test]
+    fn drains_in_timestamp_order() {
+        let mut buffer = ReorderBuffer::new(Duration::seconds(60));
+        buffer.push(event("c", Some("2024-01-01T10:00:03Z")));
+        buffer.push(event("a", Some("2024-01-01T10:00:01Z")));
+        buffer.push(event("b", Some("2024-01-01T10:00:02Z")));
+
+        assert_eq!(ids(&buffer.drain()), ["a", "b", "c"]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn keeps_arrival_order_for_equal_timestamps() {
+        let mut buffer = ReorderBuffer::new(Duration::seconds(60));
+        for id in ["x", "y", "z"] {
+            buffer.push(event(id, Some("2024-01-01T10:00:00Z")));
+        }
+        assert_eq!(ids(&buffer.drain()), ["x", "y", "z"]);
+    }
+
+    #[test]
+    fn passes_events_without_a_timestamp_straight_through() {
+        let mut buffer = ReorderBuffer::new(Duration::seconds(60));
+        buffer.push(event("a", Some("2024-01-01T10:00:00Z")));
+
+        let ready = buffer.push(event("b", None));
+        assert_eq!(ids(&ready), ["b"]);
+        let ready = buffer.push(event("c", Some("not a timestamp")));
+        assert_eq!(ids(&ready), ["c"]);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn passes_late_events_through_after_release() {
+        let mut buffer = ReorderBuffer::new(Duration::seconds(10));
+        buffer.push(event("a", Some("2024-01-01T10:00:00Z")));
+        let ready = buffer.push(event("b", Some("2024-01-01T10:00:20Z")));
+        assert_eq!(ids(&ready), ["a"]);
+
+        // Older than what's already been released, so it can't be put back in order
+        let ready = buffer.push(event("late", Some("2024-01-01T09:59:59Z")));
+        assert_eq!(ids(&ready), ["late"]);
+        assert_eq!(ids(&buffer.drain()), ["b"]);
+    }
+}
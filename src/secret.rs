@@ -0,0 +1,272 @@
+use std::{path::PathBuf, process::Stdio, str::FromStr};
+
+use tracing::debug;
+
+use crate::Error;
+
+/// The service secrets are stored under in the OS keyring, unless another is given
+pub const KEYRING_SERVICE: &str = "dogtail";
+
+/// Where to read a secret, like an API key, from. Errors never include the secret itself
+#[derive(Debug, Clone)]
+pub enum Secret {
+    /// An environment variable
+    Env(String),
+    /// The contents of a file, without surrounding whitespace
+    File(PathBuf),
+    /// The OS keyring - the keychain on macOS, the credential manager on windows, or the secret
+    /// service (e.g. gnome-keyring or kwallet) elsewhere. Without a user, the secret's name is used
+    Keyring {
+        service: String,
+        user: Option<String>,
+    },
+    /// The output of a shell command, without surrounding whitespace. The command can prompt
+    /// on stderr, e.g. for a passphrase
+    Command(String),
+}
+
+impl Secret {
+    /// Read the secret. `name` is what the secret is for, e.g. "api_key", and is used in errors
+    /// and as the default keyring user
+    pub async fn read(&self, name: &str) -> Result<String, Error> {
+        let secret = match self {
+            Secret::Env(var) => std::env::var(var).map_err(|_| {
+                Error::Credentials(format!("Expected {} in the {} env var", name, var))
+            })?,
+            Secret::File(path) => tokio::fs::read_to_string(path).await.map_err(|e| {
+                Error::Credentials(format!(
+                    "Couldn't read {} from {}: {}",
+                    name,
+                    path.display(),
+                    e
+                ))
+            })?,
+            Secret::Keyring { service, user } => {
+                let user = user.clone().unwrap_or(name.to_string());
+                let keyring_error = |e: &dyn std::fmt::Display| {
+                    Error::Credentials(format!(
+                        "Couldn't read {} from keyring service {}, user {}: {}",
+                        name, service, user, e
+                    ))
+                };
+                let entry = keyring::Entry::new(service, &user).map_err(|e| keyring_error(&e))?;
+                // The keyring clients block, and some of them run their own executors
+                tokio::task::spawn_blocking(move || entry.get_password())
+                    .await
+                    .map_err(|e| keyring_error(&e))?
+                    .map_err(|e| keyring_error(&e))?
+            }
+            Secret::Command(command) => {
+                debug!("Running credential command for {}", name);
+                let output = tokio::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::inherit())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .await
+                    .map_err(|e| {
+                        Error::Credentials(format!(
+                            "Couldn't run credential command for {}: {}",
+                            name, e
+                        ))
+                    })?;
+                if !output.status.success() {
+                    return Err(Error::Credentials(format!(
+                        "Credential command for {} failed with {}",
+                        name, output.status
+                    )));
+                }
+                String::from_utf8(output.stdout).map_err(|_| {
+                    Error::Credentials(format!(
+                        "Credential command for {} printed invalid utf-8",
+                        name
+                    ))
+                })?
+            }
+        };
+
+        let secret = secret.trim();
+        if secret.is_empty() {
+            return Err(Error::Credentials(format!(
+                "{} from {} is empty",
+                name, self
+            )));
+        }
+        Ok(secret.to_string())
+    }
+
+    /// Save a secret to the OS keyring
+    pub async fn store_in_keyring(service: &str, user: &str, secret: &str) -> Result<(), Error> {
+        let keyring_error = |e: &dyn std::fmt::Display| {
+            Error::Credentials(format!(
+                "Couldn't save to keyring service {}, user {}: {}",
+                service, user, e
+            ))
+        };
+        let entry = keyring::Entry::new(service, user).map_err(|e| keyring_error(&e))?;
+        let secret = secret.to_string();
+        tokio::task::spawn_blocking(move || entry.set_password(&secret))
+            .await
+            .map_err(|e| keyring_error(&e))?
+            .map_err(|e| keyring_error(&e))
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Error;
+
+    /// Parse "env:VAR", "file:PATH", "keyring", "keyring:SERVICE", "keyring:SERVICE/USER" or
+    /// "command:COMMAND"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, rest) = s.split_once(':').unwrap_or((s, ""));
+        match (kind, rest) {
+            ("env", var) if !var.is_empty() => Ok(Secret::Env(var.to_string())),
+            ("file", path) if !path.is_empty() => Ok(Secret::File(path.into())),
+            ("keyring", "") => Ok(Secret::Keyring {
+                service: KEYRING_SERVICE.to_string(),
+                user: None,
+            }),
+            ("keyring", rest) => {
+                let (service, user) = match rest.split_once('/') {
+                    Some((service, user)) => (service, Some(user.to_string())),
+                    None => (rest, None),
+                };
+                Ok(Secret::Keyring {
+                    service: service.to_string(),
+                    user,
+                })
+            }
+            ("command", command) if !command.is_empty() => {
+                Ok(Secret::Command(command.to_string()))
+            }
+            _ => Err(Error::Invalid(format!(
+                "Expected a secret as env:VAR, file:PATH, keyring[:SERVICE[/USER]] or command:COMMAND, got {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Secret::Env(var) => write!(f, "env var {}", var),
+            Secret::File(path) => write!(f, "file {}", path.display()),
+            Secret::Keyring { service, .. } => write!(f, "keyring service {}", service),
+            Secret::Command(_) => write!(f, "credential command"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("dogtail-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn parses_each_form() {
+        assert!(matches!("env:DD_KEY".parse(), Ok(Secret::Env(var)) if var == "DD_KEY"));
+        assert!(
+            matches!("file:/tmp/key".parse(), Ok(Secret::File(path)) if path == Path::new("/tmp/key"))
+        );
+        assert!(matches!(
+            "keyring".parse(),
+            Ok(Secret::Keyring { service, user: None }) if service == KEYRING_SERVICE
+        ));
+        assert!(matches!(
+            "keyring:svc".parse(),
+            Ok(Secret::Keyring { service, user: None }) if service == "svc"
+        ));
+        assert!(matches!(
+            "keyring:svc/user".parse(),
+            Ok(Secret::Keyring { service, user: Some(user) }) if service == "svc" && user == "user"
+        ));
+        assert!(matches!(
+            "command:pass show dd:api".parse(),
+            Ok(Secret::Command(command)) if command == "pass show dd:api"
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_forms() {
+        for s in [
+            "",
+            "env:",
+            "file:",
+            "command:",
+            "vault:secret",
+            "DD_API_KEY",
+        ] {
+            assert!(s.parse::<Secret>().is_err(), "{:?} was accepted", s);
+        }
+    }
+
+    #[tokio::test]
+    async fn trims_secrets_from_files_and_commands() {
+        let path = temp_file("trimmed", "  file-key \n");
+        assert_eq!(
+            Secret::File(path.clone()).read("api_key").await.unwrap(),
+            "file-key"
+        );
+        std::fs::remove_file(path).unwrap();
+
+        let command = Secret::Command("printf ' command-key \\n'".to_string());
+        assert_eq!(command.read("api_key").await.unwrap(), "command-key");
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_secrets() {
+        let path = temp_file("empty", " \n");
+        let err = Secret::File(path.clone())
+            .read("api_key")
+            .await
+            .unwrap_err();
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(err, Error::Credentials(_)));
+        assert!(err.to_string().contains("is empty"), "{}", err);
+
+        let err = Secret::Command("true".to_string())
+            .read("app_key")
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is empty"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn keeps_failing_commands_output_out_of_errors() {
+        let err = Secret::Command("echo leaked-key; exit 3".to_string())
+            .read("api_key")
+            .await
+            .unwrap_err();
+        assert!(!err.to_string().contains("leaked-key"), "{}", err);
+    }
+
+    // The real keyring backends need an OS service, so this runs against keyring's in-process
+    // mock, which keeps nothing between entries, to check the errors we build around it
+    #[tokio::test]
+    async fn reports_keyring_service_and_user_in_errors() {
+        keyring::set_default_credential_builder(keyring::mock::default_credential_builder());
+
+        Secret::store_in_keyring("svc", "user", "stored-key")
+            .await
+            .unwrap();
+
+        let secret = Secret::Keyring {
+            service: "svc".to_string(),
+            user: None,
+        };
+        let err = secret.read("api_key").await.unwrap_err();
+        assert!(matches!(err, Error::Credentials(_)));
+        let err = err.to_string();
+        assert!(err.contains("keyring service svc, user api_key"), "{}", err);
+        assert!(!err.contains("stored-key"), "{}", err);
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Duration, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Receiver};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info};
+
+use crate::{tailer::Tailer, time, Error};
+
+/// One slice of a larger time range, and the tailer that fetches it
+pub struct Shard {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub tailer: Tailer,
+}
+
+/// How often, in events, each shard reports its progress
+const PROGRESS_EVERY: usize = 10_000;
+
+/// How many events each shard can fetch ahead of the shards before it being written out
+const SHARD_BUFFER: usize = 10_000;
+
+/// Run all the shards concurrently, returning a stream of their events in time order.
+///
+/// Shards are expected to be consecutive, and each to emit its events sorted by time. Later
+/// shards fetch ahead while earlier ones are being written out, up to a buffer of events each,
+/// and then wait, so memory use doesn't grow with the size of the export. The tailers should
+/// share a [crate::ratelimit::RateLimitCoordinator] to keep the later shards from starving the
+/// earlier ones of requests. Events that turn up on both sides of a boundary between shards
+/// are only emitted once. If any shard fails, its error is emitted once the shards before it
+/// are done, and the stream ends there, since carrying on would leave a gap in the output.
+pub fn start_sharded(shards: Vec<Shard>) -> BoxStream<'static, Result<Value, Error>> {
+    let count = shards.len();
+    let mut windows = Vec::with_capacity(count);
+    let mut buffers = Vec::with_capacity(count);
+
+    for (i, shard) in shards.into_iter().enumerate() {
+        windows.push((shard.start, shard.end));
+        let (send, recv) = mpsc::channel(SHARD_BUFFER);
+        buffers.push(recv);
+
+        let mut events = shard.tailer.into_stream();
+        let (start, end) = (shard.start, shard.end);
+        tokio::spawn(async move {
+            info!("Shard {}/{} [{} - {}] started", i + 1, count, start, end);
+            let mut received = 0;
+            while let Some(event) = events.next().await {
+                if let Err(e) = &event {
+                    error!(
+                        "Shard {}/{} [{} - {}] failed: {}",
+                        i + 1,
+                        count,
+                        start,
+                        end,
+                        e
+                    );
+                    let _ = send.send(event).await;
+                    return;
+                }
+                received += 1;
+                if received % PROGRESS_EVERY == 0 {
+                    info!("Shard {}/{}: {} events so far", i + 1, count, received);
+                }
+                if send.send(event).await.is_err() {
+                    return;
+                }
+            }
+            info!("Shard {}/{} finished, {} events", i + 1, count, received);
+        });
+    }
+
+    let (send, recv) = mpsc::channel(100);
+    tokio::spawn(reassemble(windows, buffers, send));
+    ReceiverStream::new(recv).boxed()
+}
+
+async fn reassemble(
+    windows: Vec<(DateTime<Utc>, DateTime<Utc>)>,
+    buffers: Vec<Receiver<Result<Value, Error>>>,
+    send: mpsc::Sender<Result<Value, Error>>,
+) {
+    let timestamp_key = crate::JsonKey::from("attributes.timestamp");
+    // Ids of events close enough to the end of the previous shard that the next one might
+    // return them too
+    let mut boundary_ids = HashSet::new();
+
+    for ((_, end), mut buffer) in windows.into_iter().zip(buffers) {
+        let mut next_boundary_ids = HashSet::new();
+        let near_end = end - Duration::seconds(1);
+
+        while let Some(event) = buffer.recv().await {
+            let event = match event {
+                Ok(event) => event,
+                // Dropping the other buffers on the way out stops the remaining shards
+                Err(e) => {
+                    let _ = send.send(Err(e)).await;
+                    return;
+                }
+            };
+            let id = event["id"].as_str().map(|s| s.to_string());
+            if id.as_ref().is_some_and(|id| boundary_ids.contains(id)) {
+                continue;
+            }
+            let timestamp = timestamp_key
+                .get(&event)
+                .and_then(|t| time::parse_timestamp(&t));
+            if let (Some(id), Some(timestamp)) = (id, timestamp) {
+                if timestamp >= near_end {
+                    next_boundary_ids.insert(id);
+                }
+            }
+            if send.send(Ok(event)).await.is_err() {
+                return;
+            }
+        }
+
+        boundary_ids = next_boundary_ids;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        logs::{LogSource, Snapshot},
+        transport::fake::{response, with_rate_limit, FakeTransport},
+    };
+
+    fn at(time: &str) -> DateTime<Utc> {
+        format!("2024-01-01T{}Z", time).parse().unwrap()
+    }
+
+    fn event(id: &str, time: &str) -> Value {
+        json!({"id": id, "attributes": {"timestamp": format!("2024-01-01T{}Z", time)}})
+    }
+
+    fn url(shard: usize) -> String {
+        format!(
+            "https://shard{}.example.com/api/v2/logs/events/search",
+            shard
+        )
+    }
+
+    // Each shard is fetched from its own domain, so the fake can tell them apart
+    fn shards(windows: &[(&str, &str)], transport: FakeTransport) -> Vec<Shard> {
+        let transport = Arc::new(transport);
+        windows
+            .iter()
+            .enumerate()
+            .map(|(i, (start, end))| {
+                let (start, end) = (at(start), at(end));
+                let source = LogSource::new(
+                    format!("shard{}.example.com", i),
+                    "*".to_string(),
+                    Snapshot::new(start, end),
+                );
+                let tailer = Tailer::new("api".to_string(), "app".to_string(), Box::new(source))
+                    .with_transport(transport.clone());
+                Shard { start, end, tailer }
+            })
+            .collect()
+    }
+
+    fn page(events: Vec<Value>) -> crate::transport::Response {
+        with_rate_limit(response(200, json!({ "data": events })), 99, 1)
+    }
+
+    fn id(event: &Result<Value, Error>) -> &str {
+        event.as_ref().unwrap()["id"].as_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn emits_events_on_shard_boundaries_once() {
+        let windows = [
+            ("10:00:00", "10:01:00"),
+            ("10:01:00", "10:02:00"),
+            ("10:02:00", "10:03:00"),
+        ];
+        // The API's windows include both ends, so neighbouring shards both return events
+        // right on the boundary
+        let transport = FakeTransport::default()
+            .with_response(
+                &url(0),
+                page(vec![
+                    event("a", "10:00:10"),
+                    event("b", "10:00:59.500"),
+                    event("c", "10:01:00"),
+                ]),
+            )
+            .with_response(
+                &url(1),
+                page(vec![
+                    event("c", "10:01:00"),
+                    event("d", "10:01:30"),
+                    event("e", "10:02:00"),
+                ]),
+            )
+            .with_response(
+                &url(2),
+                page(vec![event("e", "10:02:00"), event("f", "10:02:30")]),
+            );
+
+        let events: Vec<_> = start_sharded(shards(&windows, transport)).collect().await;
+        let ids: Vec<_> = events.iter().map(id).collect();
+        assert_eq!(ids, ["a", "b", "c", "d", "e", "f"]);
+    }
+
+    #[tokio::test]
+    async fn ends_at_the_first_failed_shard() {
+        let windows = [
+            ("10:00:00", "10:01:00"),
+            ("10:01:00", "10:02:00"),
+            ("10:02:00", "10:03:00"),
+        ];
+        // Nothing for the second shard, so its request fails
+        let transport = FakeTransport::default()
+            .with_response(&url(0), page(vec![event("a", "10:00:10")]))
+            .with_response(&url(2), page(vec![event("c", "10:02:30")]));
+
+        let events: Vec<_> = start_sharded(shards(&windows, transport)).collect().await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(id(&events[0]), "a");
+        assert!(matches!(events[1], Err(Error::Transport { .. })));
+    }
+}
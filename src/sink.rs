@@ -1,10 +1,19 @@
 use std::collections::HashMap;
+use std::{fmt, io, path::PathBuf, process::ExitStatus};
 
 use futures::future::join_all;
 use serde_json::Value;
 use std::time::Duration;
 use tokio::{runtime, sync::mpsc, task::JoinHandle};
 
+use crate::{Error, JsonKey};
+
+pub mod exec;
+pub mod http;
+pub mod net;
+pub mod parquet;
+pub mod sqlite;
+
 /// A thing that knows how to construct an output stream given a value,
 /// and how to construct an output ID from an event. A "SinkSet" is really
 /// more like an abstraction over a set of possible consumers, and is used in combination
@@ -20,11 +29,24 @@ pub trait SinkSet: Send + Sync {
     fn get_sink_id(&self, event: &Value) -> String;
 }
 
+/// The sink id for an event: the string at `split_key`, or `default` if there isn't a split key,
+/// or the event doesn't have it. This is how most [SinkSet]s implement [SinkSet::get_sink_id]
+pub fn split_sink_id(split_key: Option<&JsonKey>, event: &Value, default: &str) -> String {
+    split_key
+        .and_then(|key| key.get(event))
+        .and_then(|v| v.as_str().map(|s| s.to_string()))
+        .unwrap_or_else(|| default.to_string())
+}
+
+/// The longest sinks that reconnect or restart their output wait between attempts
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 /// An "actor" that consumes messages, and exits when the other side of the channel
-/// returns None
+/// returns None. If the actor hits an error it returns it from its task, which the
+/// owning [ConsumerPool] picks up the next time it talks to the sink
 pub struct Sink {
     id: String,
-    handle: JoinHandle<()>,
+    handle: JoinHandle<Result<(), SinkError>>,
     sender: mpsc::Sender<SinkMessage>,
 }
 
@@ -32,6 +54,81 @@ pub enum SinkMessage {
     New(Value),
 }
 
+/// Why a sink stopped consuming events
+#[derive(Debug)]
+pub enum SinkError {
+    /// Whoever was reading the output went away, e.g. `dogtail ... | head`. This isn't
+    /// really a failure, and callers will generally want to stop tailing cleanly
+    Closed { id: String },
+    /// Writing to the output failed, e.g. because the disk is full
+    Io {
+        id: String,
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// A command the sink was writing to exited
+    Exited { id: String, status: ExitStatus },
+    /// The sink task panicked or was cancelled before reporting why
+    Aborted { id: String },
+}
+
+impl SinkError {
+    /// Wrap an io error hit by the sink `id` while writing to `path` (None for stdout and
+    /// the like). Broken pipes are reported as [SinkError::Closed]
+    pub fn io(id: impl Into<String>, path: Option<PathBuf>, source: io::Error) -> Self {
+        let id = id.into();
+        match source.kind() {
+            io::ErrorKind::BrokenPipe => SinkError::Closed { id },
+            _ => SinkError::Io { id, path, source },
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            SinkError::Closed { id }
+            | SinkError::Io { id, .. }
+            | SinkError::Exited { id, .. }
+            | SinkError::Aborted { id } => id,
+        }
+    }
+
+    /// True if the sink stopped because its reader went away, rather than because of a failure
+    pub fn is_closed(&self) -> bool {
+        matches!(self, SinkError::Closed { .. })
+    }
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SinkError::Closed { id } => write!(f, "sink {}: output closed", id),
+            SinkError::Io {
+                id,
+                path: Some(path),
+                source,
+            } => write!(f, "sink {}: writing to {}: {}", id, path.display(), source),
+            SinkError::Io {
+                id,
+                path: None,
+                source,
+            } => write!(f, "sink {}: {}", id, source),
+            SinkError::Exited { id, status } => {
+                write!(f, "sink {}: command exited with {}", id, status)
+            }
+            SinkError::Aborted { id } => write!(f, "sink {}: task aborted", id),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SinkError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
 /// A thing which can consume values, and dispatch them to the correct output
 /// stream. I really wish I had a better name for this
 pub struct ConsumerPool {
@@ -47,49 +144,112 @@ impl ConsumerPool {
         }
     }
 
-    /// Consume an event, dispatching it to the correct output stream
+    /// Consume an event, dispatching it to the correct output stream. If the output
+    /// stream has exited, the error it exited with is returned, and the sink is dropped
+    /// from the pool
     #[tracing::instrument(level = "trace", skip(self, event))]
-    pub async fn consume(&mut self, event: Value) -> Result<(), anyhow::Error> {
+    pub async fn consume(&mut self, event: Value) -> Result<(), Error> {
         let id = self.sink_set.get_sink_id(&event);
 
-        let sink = self.sinks.entry(id).or_insert_with(|| {
+        let sink = self.sinks.entry(id.clone()).or_insert_with(|| {
             self.sink_set
                 .construct_output(&event, &tokio::runtime::Handle::current())
         });
 
-        sink.send(SinkMessage::New(event)).await?;
+        if sink.send(SinkMessage::New(event)).await.is_err() {
+            // The receiving side only goes away when the task has exited, so it's
+            // safe to wait on it here
+            let sink = self.sinks.remove(&id).expect("Sink was just used");
+            return Err(sink
+                .join()
+                .await
+                .err()
+                .unwrap_or(SinkError::Aborted { id })
+                .into());
+        }
 
         Ok(())
     }
 
     /// Drop all output stream channels and join all output streams, waiting at most
-    /// `wait` seconds for them to finish
-    pub async fn finish(mut self, wait: u64) {
+    /// `wait` seconds for them to finish. Returns the first error any of them exited with
+    pub async fn finish(mut self, wait: u64) -> Result<(), Error> {
         join_all(
             self.sinks
                 .drain()
                 .map(|(_, s)| s.finish(Duration::from_secs(wait))),
         )
-        .await;
+        .await
+        .into_iter()
+        .collect::<Result<(), SinkError>>()
+        .map_err(Error::from)
     }
 }
 
 impl Sink {
-    pub fn new(id: String, handle: JoinHandle<()>, sender: mpsc::Sender<SinkMessage>) -> Self {
+    pub fn new(
+        id: String,
+        handle: JoinHandle<Result<(), SinkError>>,
+        sender: mpsc::Sender<SinkMessage>,
+    ) -> Self {
         Sink { id, handle, sender }
     }
 
-    pub async fn send(&self, value: SinkMessage) -> Result<(), anyhow::Error> {
-        self.sender.send(value).await?;
-        Ok(())
+    /// Send a message to the sink. This only fails if the sink has exited
+    pub async fn send(&self, value: SinkMessage) -> Result<(), Error> {
+        self.sender.send(value).await.map_err(|_| Error::Closed)
+    }
+
+    /// Close the channel and wait at most `wait` for the sink to exit. Timing out
+    /// isn't treated as an error
+    pub async fn finish(self, wait: Duration) -> Result<(), SinkError> {
+        let Sink { id, handle, sender } = self;
+        drop(sender);
+        match tokio::time::timeout(wait, handle).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(_)) => Err(SinkError::Aborted { id }),
+            Err(_) => Ok(()),
+        }
     }
 
-    pub async fn finish(self, wait: Duration) {
-        drop(self.sender);
-        let _ = tokio::time::timeout(wait, self.handle).await;
+    async fn join(self) -> Result<(), SinkError> {
+        let Sink { id, handle, .. } = self;
+        handle.await.unwrap_or(Err(SinkError::Aborted { id }))
     }
 
     pub fn id(&self) -> &str {
         &self.id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn treats_broken_pipes_as_closed() {
+        let err = SinkError::io("out", None, io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(
+            matches!(&err, SinkError::Closed { id } if id == "out"),
+            "{}",
+            err
+        );
+        assert!(err.is_closed());
+        assert!(Error::from(err).is_closed());
+    }
+
+    #[test]
+    fn reports_other_io_errors() {
+        let path = PathBuf::from("out.log");
+        for kind in [io::ErrorKind::StorageFull, io::ErrorKind::PermissionDenied] {
+            let err = SinkError::io("out", Some(path.clone()), io::Error::from(kind));
+            assert!(
+                matches!(&err, SinkError::Io { source, .. } if source.kind() == kind),
+                "{}",
+                err
+            );
+            assert!(!err.is_closed());
+            assert_eq!(err.id(), "out");
+        }
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::{
+    io::AsyncWriteExt,
+    process::{Child, ChildStdin, Command},
+    runtime,
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use super::{split_sink_id, Sink, SinkError, SinkMessage, SinkSet, MAX_BACKOFF};
+use crate::{logs::LogFormat, JsonKey};
+
+/// The environment variable the sink id is passed to each command in
+pub const SINK_ID_VAR: &str = "DOGTAIL_SINK_ID";
+
+/// A [SinkSet] that spawns a command per sink id, and writes formatted events to its stdin, so
+/// each split stream can be put through its own `jq` or alerting script.
+///
+/// The sink id is available to the command in the `DOGTAIL_SINK_ID` environment variable, and
+/// any argument that is exactly `{}` is replaced with it. If the command exits while there are
+/// still events to write it's either restarted, or the sink stops. A command that exits cleanly,
+/// like `head`, is treated as having closed its input ([SinkError::Closed]), while any other exit
+/// is reported as a [SinkError::Exited].
+/// When the [super::ConsumerPool] finishes, each command's stdin is closed and it's waited on.
+pub struct ExecSinkSet {
+    program: String,
+    args: Vec<String>,
+    restart: bool,
+    split_key: Option<JsonKey>,
+    default: String,
+    format: LogFormat,
+}
+
+impl ExecSinkSet {
+    pub fn new(
+        program: String,
+        args: Vec<String>,
+        restart: bool,
+        split_key: Option<JsonKey>,
+        default: String,
+        format: LogFormat,
+    ) -> Self {
+        ExecSinkSet {
+            program,
+            args,
+            restart,
+            split_key,
+            default,
+            format,
+        }
+    }
+}
+
+impl SinkSet for ExecSinkSet {
+    fn construct_output(&self, event: &Value, runtime: &runtime::Handle) -> Sink {
+        let id = self.get_sink_id(event);
+        let (tx, rx) = mpsc::channel(100);
+        let mut command = Command::new(&self.program);
+        command
+            .args(self.args.iter().map(|a| if a == "{}" { &id } else { a }))
+            .env(SINK_ID_VAR, &id)
+            .stdin(Stdio::piped())
+            .kill_on_drop(true);
+        let handle = runtime.spawn(exec_writer(
+            id.clone(),
+            command,
+            self.restart,
+            self.format.clone(),
+            rx,
+        ));
+        Sink::new(id, handle, tx)
+    }
+
+    fn get_sink_id(&self, event: &Value) -> String {
+        split_sink_id(self.split_key.as_ref(), event, &self.default)
+    }
+}
+
+async fn exec_writer(
+    writer_id: String,
+    mut command: Command,
+    restart: bool,
+    format: LogFormat,
+    mut recv: mpsc::Receiver<SinkMessage>,
+) -> Result<(), SinkError> {
+    let spawn = |command: &mut Command| -> Result<(Child, ChildStdin), SinkError> {
+        let mut child = command
+            .spawn()
+            .map_err(|e| SinkError::io(writer_id.as_str(), None, e))?;
+        let stdin = child.stdin.take().expect("Stdin is always piped");
+        info!("Started command for {}, pid {:?}", writer_id, child.id());
+        Ok((child, stdin))
+    };
+    let (mut child, mut stdin) = spawn(&mut command)?;
+
+    while let Some(msg) = recv.recv().await {
+        match msg {
+            SinkMessage::New(event) => {
+                let line = format!("{}\n", format.format(&event));
+                let mut backoff = Duration::from_millis(250);
+                // A failed write means the child has gone away, so it's either restarted and
+                // handed the same line, or we give up
+                while stdin.write_all(line.as_bytes()).await.is_err() {
+                    let status = child
+                        .wait()
+                        .await
+                        .map_err(|e| SinkError::io(writer_id.as_str(), None, e))?;
+                    if !restart {
+                        return Err(exited(writer_id, status));
+                    }
+                    warn!(
+                        "Command for {} exited with {}, restarting in {}ms",
+                        writer_id,
+                        status,
+                        backoff.as_millis()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    (child, stdin) = spawn(&mut command)?;
+                }
+            }
+        }
+    }
+
+    // Closing stdin is the command's cue to finish up
+    drop(stdin);
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| SinkError::io(writer_id.as_str(), None, e))?;
+    info!("Command for {} exited with {}", writer_id, status);
+    if !status.success() {
+        return Err(exited(writer_id, status));
+    }
+    Ok(())
+}
+
+/// A command that exits cleanly has just stopped reading, the same as a closed pipe
+fn exited(id: String, status: ExitStatus) -> SinkError {
+    if status.success() {
+        SinkError::Closed { id }
+    } else {
+        SinkError::Exited { id, status }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use serde_json::json;
+
+    use super::*;
+
+    fn command(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", script])
+            .stdin(Stdio::piped())
+            .kill_on_drop(true);
+        command
+    }
+
+    // Keep sending events until the writer stops, returning how it stopped
+    async fn run(script: &str) -> Result<(), SinkError> {
+        let (tx, rx) = mpsc::channel(1);
+        let format = LogFormat::text(" ".to_string(), vec![JsonKey::from("id")]);
+        let writer = tokio::spawn(exec_writer(
+            "out".to_string(),
+            command(script),
+            false,
+            format,
+            rx,
+        ));
+        for _ in 0..10_000 {
+            if tx.send(SinkMessage::New(json!({"id": "a"}))).await.is_err() {
+                break;
+            }
+        }
+        drop(tx);
+        writer.await.unwrap()
+    }
+
+    #[test]
+    fn maps_exit_statuses() {
+        let clean = exited("out".to_string(), ExitStatus::from_raw(0));
+        assert!(clean.is_closed(), "{}", clean);
+
+        let failed = exited("out".to_string(), ExitStatus::from_raw(3 << 8));
+        assert!(
+            matches!(failed, SinkError::Exited { status, .. } if status.code() == Some(3)),
+            "{}",
+            failed
+        );
+
+        // Killed by SIGKILL
+        let killed = exited("out".to_string(), ExitStatus::from_raw(9));
+        assert!(matches!(killed, SinkError::Exited { .. }), "{}", killed);
+    }
+
+    #[tokio::test]
+    async fn treats_a_clean_exit_as_closed() {
+        let err = run("head -n 1 >/dev/null").await.unwrap_err();
+        assert!(err.is_closed(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn reports_a_failed_exit() {
+        let err = run("head -n 1 >/dev/null; exit 3").await.unwrap_err();
+        assert!(matches!(err, SinkError::Exited { .. }), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn checks_the_exit_status_once_finished() {
+        assert!(run("cat >/dev/null").await.is_ok());
+        let err = run("cat >/dev/null; exit 1").await.unwrap_err();
+        assert!(matches!(err, SinkError::Exited { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,282 @@
+use std::{collections::HashSet, convert::Infallible, net::SocketAddr, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        RawQuery, State,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::get,
+    Router,
+};
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use tokio::{
+    runtime,
+    sync::{broadcast, mpsc},
+};
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, warn};
+
+use super::{split_sink_id, Sink, SinkError, SinkMessage, SinkSet};
+use crate::{logs::LogFormat, JsonKey};
+
+/// A [SinkSet] that serves events to any number of local clients over HTTP, so one dogtail
+/// process can fan out to many consumers without each of them spending API rate limit.
+///
+/// Clients can connect to `/events` for server-sent events, or `/ws` for a websocket. Both
+/// accept an optional `split` query parameter, a comma separated list of sink ids (the value
+/// of the split key), to only receive some of the events, e.g. `/events?split=pod-a,pod-b`.
+pub struct HttpSinkSet {
+    split_key: Option<JsonKey>,
+    default: String,
+    format: LogFormat,
+    sender: broadcast::Sender<Arc<Line>>,
+    addr: SocketAddr,
+}
+
+/// A formatted event, and the id of the sink it was dispatched to
+#[derive(Debug)]
+struct Line {
+    sink_id: String,
+    text: String,
+}
+
+impl HttpSinkSet {
+    /// Bind to `addr` and start serving. Events without the split key (or all events, if there
+    /// isn't one) are given the sink id `default`. Clients that fall more than `buffer` events
+    /// behind skip ahead, rather than slowing down the tail.
+    pub async fn bind(
+        addr: SocketAddr,
+        split_key: Option<JsonKey>,
+        default: String,
+        format: LogFormat,
+        buffer: usize,
+    ) -> Result<Self, std::io::Error> {
+        let (sender, _) = broadcast::channel(buffer);
+
+        let app = Router::new()
+            .route("/events", get(sse_handler))
+            .route("/ws", get(ws_handler))
+            .with_state(sender.clone());
+
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let server = axum::Server::from_tcp(listener)
+            .map_err(std::io::Error::other)?
+            .serve(app.into_make_service());
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                warn!("Http sink server exited: {}", e);
+            }
+        });
+        info!("Serving events on http://{}", addr);
+
+        Ok(HttpSinkSet {
+            split_key,
+            default,
+            format,
+            sender,
+            addr,
+        })
+    }
+
+    /// The address the server is actually listening on, useful when binding to port 0
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl SinkSet for HttpSinkSet {
+    fn construct_output(&self, event: &Value, runtime: &runtime::Handle) -> Sink {
+        let id = self.get_sink_id(event);
+        let (tx, rx) = mpsc::channel(100);
+        let handle = runtime.spawn(broadcaster(
+            id.clone(),
+            self.format.clone(),
+            self.sender.clone(),
+            rx,
+        ));
+        Sink::new(id, handle, tx)
+    }
+
+    fn get_sink_id(&self, event: &Value) -> String {
+        split_sink_id(self.split_key.as_ref(), event, &self.default)
+    }
+}
+
+async fn broadcaster(
+    sink_id: String,
+    format: LogFormat,
+    sender: broadcast::Sender<Arc<Line>>,
+    mut recv: mpsc::Receiver<SinkMessage>,
+) -> Result<(), SinkError> {
+    while let Some(msg) = recv.recv().await {
+        match msg {
+            SinkMessage::New(event) => {
+                let line = Line {
+                    sink_id: sink_id.clone(),
+                    text: format.format(&event),
+                };
+                // An error here just means nobody is connected right now, which is fine
+                let _ = sender.send(Arc::new(line));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Subscribe to the broadcast, keeping only the lines the client asked for
+fn subscribe(
+    sender: &broadcast::Sender<Arc<Line>>,
+    query: Option<String>,
+) -> impl Stream<Item = Arc<Line>> {
+    let filter = parse_filter(query);
+    BroadcastStream::new(sender.subscribe()).filter_map(move |line| {
+        let line = match line {
+            Ok(line) => Some(line),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                warn!("Http client fell behind, skipped {} events", n);
+                None
+            }
+        };
+        let line = line.filter(|l| filter.as_ref().is_none_or(|f| f.contains(&l.sink_id)));
+        futures::future::ready(line)
+    })
+}
+
+fn parse_filter(query: Option<String>) -> Option<HashSet<String>> {
+    let query = query?;
+    let ids: HashSet<_> = query
+        .split('&')
+        .filter_map(|pair| pair.strip_prefix("split="))
+        .flat_map(|ids| ids.split(','))
+        .filter(|id| !id.is_empty())
+        .map(percent_decode)
+        .collect();
+    (!ids.is_empty()).then_some(ids)
+}
+
+// Split key values are things like pod names, so we only bother handling %XX escapes and '+'
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            // from_str_radix would also take a sign, so check for hex digits first
+            b'%' if bytes
+                .get(i + 1..i + 3)
+                .is_some_and(|h| h.iter().all(u8::is_ascii_hexdigit)) =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                out.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+                continue;
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+async fn sse_handler(
+    State(sender): State<broadcast::Sender<Arc<Line>>>,
+    RawQuery(query): RawQuery,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = subscribe(&sender, query).map(|line| {
+        // Event names can't span lines, and split key values come from whatever was logged
+        let name = line.sink_id.replace(['\r', '\n'], " ");
+        Ok(Event::default().event(name).data(line.text.as_str()))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+async fn ws_handler(
+    State(sender): State<broadcast::Sender<Arc<Line>>>,
+    RawQuery(query): RawQuery,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let lines = subscribe(&sender, query);
+    ws.on_upgrade(move |socket| forward_to_socket(socket, lines))
+}
+
+async fn forward_to_socket(mut socket: WebSocket, lines: impl Stream<Item = Arc<Line>>) {
+    futures::pin_mut!(lines);
+    loop {
+        tokio::select! {
+            line = lines.next() => {
+                let Some(line) = line else {
+                    break;
+                };
+                if socket.send(Message::Text(line.text.clone())).await.is_err() {
+                    break;
+                }
+            }
+            // We don't expect clients to say anything, but we do need to notice them leaving
+            msg = socket.recv() => {
+                match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(query: &str) -> Option<Vec<String>> {
+        parse_filter(Some(query.to_string())).map(|ids| {
+            let mut ids: Vec<_> = ids.into_iter().collect();
+            ids.sort();
+            ids
+        })
+    }
+
+    #[test]
+    fn parses_split_filters() {
+        assert_eq!(parse_filter(None), None);
+        assert_eq!(filter("other=1"), None);
+        assert_eq!(filter("split="), None);
+        assert_eq!(filter("split=a"), Some(vec!["a".to_string()]));
+        assert_eq!(
+            filter("split=b,a&other=1&split=c,"),
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+        assert_eq!(
+            filter("split=a%2Cb"),
+            Some(vec!["a,b".to_string()]),
+            "escaped commas are part of the id"
+        );
+    }
+
+    #[test]
+    fn percent_decodes() {
+        let cases = [
+            ("web-1", "web-1"),
+            ("a%20b", "a b"),
+            ("a+b", "a b"),
+            ("a%2Bb", "a+b"),
+            ("%e2%9C%93", "\u{2713}"),
+            ("100%", "100%"),
+            ("a%4", "a%4"),
+            ("%zz", "%zz"),
+            ("%+1", "% 1"),
+            ("%-1", "%-1"),
+            ("%%41", "%A"),
+            ("%ff", "\u{fffd}"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(percent_decode(input), expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde_json::Value;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpStream, UdpSocket, UnixStream},
+    runtime,
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use super::{split_sink_id, Sink, SinkError, SinkMessage, SinkSet, MAX_BACKOFF};
+use crate::{logs::LogFormat, time, JsonKey};
+
+/// Where a [NetSinkSet] sends events
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// A `host:port` to connect to over TCP
+    Tcp(String),
+    /// A `host:port` to send datagrams to
+    Udp(String),
+    /// The path of a unix stream socket
+    Unix(PathBuf),
+}
+
+/// How events are written to the target
+#[derive(Debug, Clone, Copy)]
+pub enum Framing {
+    /// One formatted event per line
+    Lines,
+    /// RFC 5424 syslog messages. Over TCP these are octet-counted, as per RFC 6587, otherwise
+    /// each message is written as a single datagram or line
+    Syslog,
+}
+
+/// A [SinkSet] that forwards events over the network, or a unix socket, to local tooling that's
+/// already listening. Each sink gets its own connection, which is re-established (with backoff)
+/// whenever writing to it fails, so a restarting listener doesn't stop the tail. If an event
+/// still can't be written after a couple of minutes of retrying, the sink fails.
+pub struct NetSinkSet {
+    target: Target,
+    framing: Framing,
+    split_key: Option<JsonKey>,
+    default: String,
+    format: LogFormat,
+}
+
+impl NetSinkSet {
+    pub fn new(
+        target: Target,
+        framing: Framing,
+        split_key: Option<JsonKey>,
+        default: String,
+        format: LogFormat,
+    ) -> Self {
+        NetSinkSet {
+            target,
+            framing,
+            split_key,
+            default,
+            format,
+        }
+    }
+}
+
+/// How many times writing an event is retried before the sink gives up. With the backoff, that's
+/// about two minutes
+const MAX_RETRIES: u32 = 10;
+
+impl SinkSet for NetSinkSet {
+    fn construct_output(&self, event: &Value, runtime: &runtime::Handle) -> Sink {
+        let id = self.get_sink_id(event);
+        let (tx, rx) = mpsc::channel(100);
+        let handle = runtime.spawn(net_writer(
+            id.clone(),
+            self.target.clone(),
+            self.framing,
+            self.format.clone(),
+            rx,
+        ));
+        Sink::new(id, handle, tx)
+    }
+
+    fn get_sink_id(&self, event: &Value) -> String {
+        split_sink_id(self.split_key.as_ref(), event, &self.default)
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    async fn open(target: &Target) -> Result<Self, io::Error> {
+        Ok(match target {
+            Target::Tcp(addr) => Connection::Tcp(TcpStream::connect(addr).await?),
+            Target::Udp(addr) => {
+                let addr = lookup_host(addr).await?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, format!("{} not found", addr))
+                })?;
+                // The socket has to be of the same family as the address it sends to
+                let local: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(addr).await?;
+                Connection::Udp(socket)
+            }
+            Target::Unix(path) => Connection::Unix(UnixStream::connect(path).await?),
+        })
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<(), io::Error> {
+        match self {
+            Connection::Tcp(stream) => stream.write_all(buf).await,
+            Connection::Udp(socket) => socket.send(buf).await.map(|_| ()),
+            Connection::Unix(stream) => stream.write_all(buf).await,
+        }
+    }
+}
+
+async fn net_writer(
+    writer_id: String,
+    target: Target,
+    framing: Framing,
+    format: LogFormat,
+    mut recv: mpsc::Receiver<SinkMessage>,
+) -> Result<(), SinkError> {
+    info!("Started forwarding {} to {:?}", writer_id, target);
+    let mut connection: Option<Connection> = None;
+
+    while let Some(msg) = recv.recv().await {
+        match msg {
+            SinkMessage::New(event) => {
+                let buf = frame(&target, framing, &format, &event);
+                let mut backoff = Duration::from_millis(250);
+                let mut retries = 0;
+                loop {
+                    let res = match &mut connection {
+                        Some(conn) => conn.write(&buf).await,
+                        None => match Connection::open(&target).await {
+                            Ok(conn) => {
+                                info!("Connected {} to {:?}", writer_id, target);
+                                connection.insert(conn).write(&buf).await
+                            }
+                            Err(e) => Err(e),
+                        },
+                    };
+                    let Err(e) = res else {
+                        break;
+                    };
+                    if retries == MAX_RETRIES {
+                        return Err(SinkError::io(writer_id, None, e));
+                    }
+                    retries += 1;
+                    warn!(
+                        "Writing {} to {:?} failed, retrying in {}ms: {}",
+                        writer_id,
+                        target,
+                        backoff.as_millis(),
+                        e
+                    );
+                    connection = None;
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    if let Some(Connection::Tcp(mut stream)) = connection {
+        let _ = stream.shutdown().await;
+    }
+    info!("Finished forwarding {}", writer_id);
+    Ok(())
+}
+
+fn frame(target: &Target, framing: Framing, format: &LogFormat, event: &Value) -> Vec<u8> {
+    match (framing, target) {
+        (Framing::Lines, Target::Udp(_)) => format.format(event).into_bytes(),
+        (Framing::Lines, _) => format!("{}\n", format.format(event)).into_bytes(),
+        (Framing::Syslog, Target::Tcp(_)) => {
+            let msg = syslog_message(format, event);
+            format!("{} {}", msg.len(), msg).into_bytes()
+        }
+        (Framing::Syslog, Target::Udp(_)) => syslog_message(format, event).into_bytes(),
+        (Framing::Syslog, Target::Unix(_)) => {
+            format!("{}\n", syslog_message(format, event)).into_bytes()
+        }
+    }
+}
+
+const FACILITY_USER: u8 = 1;
+
+/// Build an RFC 5424 message, using the datadog status, host and service for the header fields,
+/// and the formatted event as the message body
+fn syslog_message(format: &LogFormat, event: &Value) -> String {
+    let attributes = &event["attributes"];
+    let pri = FACILITY_USER * 8 + severity(attributes["status"].as_str());
+    let timestamp = time::parse_timestamp(&attributes["timestamp"])
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Micros, true))
+        .unwrap_or("-".to_string());
+    let host = header_field(attributes["host"].as_str(), 255);
+    let app = header_field(attributes["service"].as_str(), 48);
+    format!(
+        "<{}>1 {} {} {} - - - {}",
+        pri,
+        timestamp,
+        host,
+        app,
+        format.format(event)
+    )
+}
+
+fn severity(status: Option<&str>) -> u8 {
+    match status.map(|s| s.to_ascii_lowercase()).as_deref() {
+        Some("emerg") | Some("emergency") => 0,
+        Some("alert") => 1,
+        Some("crit") | Some("critical") => 2,
+        Some("err") | Some("error") => 3,
+        Some("warn") | Some("warning") => 4,
+        Some("notice") => 5,
+        Some("debug") | Some("trace") => 7,
+        _ => 6, // info
+    }
+}
+
+// Header fields are printable ascii with no spaces, with a maximum length, and "-" if missing
+fn header_field(value: Option<&str>, max_len: usize) -> String {
+    let field: String = value
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::sink::ConsumerPool;
+
+    fn event() -> Value {
+        json!({
+            "id": "a",
+            "attributes": {
+                "timestamp": "2024-01-01T10:00:00.5Z",
+                "status": "Warning",
+                "host": "web 1",
+                "service": "api",
+                "message": "slow"
+            }
+        })
+    }
+
+    fn message_format() -> LogFormat {
+        LogFormat::text(" ".to_string(), vec![JsonKey::from("attributes.message")])
+    }
+
+    #[test]
+    fn frames_events_for_each_target() {
+        let format = message_format();
+        let tcp = Target::Tcp("localhost:514".to_string());
+        let udp = Target::Udp("localhost:514".to_string());
+        let unix = Target::Unix(PathBuf::from("/dev/log"));
+        let frame = |target, framing| String::from_utf8(frame(target, framing, &format, &event()));
+
+        assert_eq!(frame(&tcp, Framing::Lines).unwrap(), "slow\n");
+        assert_eq!(frame(&unix, Framing::Lines).unwrap(), "slow\n");
+        assert_eq!(frame(&udp, Framing::Lines).unwrap(), "slow");
+
+        let syslog = "<12>1 2024-01-01T10:00:00.500000Z web1 api - - - slow";
+        assert_eq!(
+            frame(&tcp, Framing::Syslog).unwrap(),
+            format!("{} {}", syslog.len(), syslog)
+        );
+        assert_eq!(frame(&udp, Framing::Syslog).unwrap(), syslog);
+        assert_eq!(
+            frame(&unix, Framing::Syslog).unwrap(),
+            format!("{}\n", syslog)
+        );
+    }
+
+    #[test]
+    fn fills_in_missing_syslog_fields() {
+        let event = json!({"attributes": {"message": "bare"}});
+        assert_eq!(
+            syslog_message(&message_format(), &event),
+            "<14>1 - - - - - - bare"
+        );
+    }
+
+    #[test]
+    fn maps_statuses_to_severities() {
+        let cases = [
+            (Some("emergency"), 0),
+            (Some("alert"), 1),
+            (Some("CRITICAL"), 2),
+            (Some("err"), 3),
+            (Some("warn"), 4),
+            (Some("notice"), 5),
+            (Some("info"), 6),
+            (Some("ok"), 6),
+            (None, 6),
+            (Some("trace"), 7),
+        ];
+        for (status, expected) in cases {
+            assert_eq!(severity(status), expected, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn limits_header_fields_to_printable_ascii() {
+        assert_eq!(header_field(Some("web 1\t"), 10), "web1");
+        assert_eq!(header_field(Some("héllo"), 10), "hllo");
+        assert_eq!(header_field(Some("abcdef"), 3), "abc");
+        assert_eq!(header_field(Some(" "), 3), "-");
+        assert_eq!(header_field(None, 3), "-");
+    }
+
+    #[tokio::test]
+    async fn sends_datagrams_to_ipv6_targets() {
+        let listener = UdpSocket::bind("[::1]:0").await.unwrap();
+        let target = Target::Udp(listener.local_addr().unwrap().to_string());
+        let sink_set = NetSinkSet::new(
+            target,
+            Framing::Lines,
+            None,
+            "out".to_string(),
+            message_format(),
+        );
+        let mut pool = ConsumerPool::new(Box::new(sink_set));
+        pool.consume(event()).await.unwrap();
+        pool.finish(5).await.unwrap();
+
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"slow");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_after_retrying() {
+        // Nothing listens on port 1, so every connection is refused
+        let target = Target::Tcp("127.0.0.1:1".to_string());
+        let (tx, rx) = mpsc::channel(1);
+        tx.send(SinkMessage::New(event())).await.unwrap();
+        drop(tx);
+
+        let err = net_writer(
+            "out".to_string(),
+            target,
+            Framing::Lines,
+            message_format(),
+            rx,
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(err, SinkError::Io { .. }), "{}", err);
+    }
+}
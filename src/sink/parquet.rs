@@ -0,0 +1,343 @@
+use std::{fs::File, path::PathBuf, sync::Arc};
+
+use arrow_array::{
+    builder::{
+        BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, TimestampMicrosecondBuilder,
+    },
+    ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use serde_json::Value;
+use tokio::{runtime, sync::mpsc};
+use tracing::{debug, info};
+
+use super::{split_sink_id, Sink, SinkError, SinkMessage, SinkSet};
+use crate::{time, Column, ColumnType, Error, JsonKey};
+
+/// A [SinkSet] that writes events to parquet files, one per sink id, for loading into
+/// analytics tools like DuckDB or Polars.
+///
+/// Each file has the configured columns, plus a `raw` column holding the event json. Columns
+/// without a type have one inferred from the first row group. Rows are buffered and written a
+/// row group at a time, and files are only valid once finalized, which happens when the
+/// [super::ConsumerPool] is finished.
+pub struct ParquetSinkSet {
+    split_key: Option<JsonKey>,
+    default: String,
+    columns: Vec<Column>,
+    row_group_size: usize,
+}
+
+impl ParquetSinkSet {
+    /// If `columns` is empty, the id, timestamp, status, service, host and message are extracted.
+    /// Files are named after the sink id (the value of the split key, or `default`), with a
+    /// `.parquet` extension added if they don't already have one. Columns can't be named `raw`,
+    /// or share a name with each other.
+    pub fn new(
+        split_key: Option<JsonKey>,
+        default: String,
+        columns: Vec<Column>,
+        row_group_size: usize,
+    ) -> Result<Self, Error> {
+        // Names are compared case insensitively, as most of the tools that read the files do
+        let mut names = vec![RAW_COLUMN.to_string()];
+        for column in &columns {
+            let name = column.name.to_lowercase();
+            if name == RAW_COLUMN {
+                return Err(Error::Invalid(format!(
+                    "Column {} clashes with the built-in {} column",
+                    column.name, RAW_COLUMN
+                )));
+            }
+            if names.contains(&name) {
+                return Err(Error::Invalid(format!(
+                    "Column {} is given more than once",
+                    column.name
+                )));
+            }
+            names.push(name);
+        }
+
+        let columns = if columns.is_empty() {
+            default_columns()
+        } else {
+            columns
+        };
+        Ok(ParquetSinkSet {
+            split_key,
+            default,
+            columns,
+            row_group_size: row_group_size.max(1),
+        })
+    }
+}
+
+/// The column holding the event json
+const RAW_COLUMN: &str = "raw";
+
+fn default_columns() -> Vec<Column> {
+    [
+        ("id", "id", ColumnType::String),
+        ("timestamp", "attributes.timestamp", ColumnType::Timestamp),
+        ("status", "attributes.status", ColumnType::String),
+        ("service", "attributes.service", ColumnType::String),
+        ("host", "attributes.host", ColumnType::String),
+        ("message", "attributes.message", ColumnType::String),
+    ]
+    .into_iter()
+    .map(|(name, key, kind)| Column::new(name.to_string(), JsonKey::from(key), Some(kind)))
+    .collect()
+}
+
+impl SinkSet for ParquetSinkSet {
+    fn construct_output(&self, event: &Value, runtime: &runtime::Handle) -> Sink {
+        let id = self.get_sink_id(event);
+        let path = if id.ends_with(".parquet") {
+            PathBuf::from(&id)
+        } else {
+            PathBuf::from(format!("{}.parquet", id))
+        };
+        let (tx, rx) = mpsc::channel(100);
+        let writer = ParquetWriter {
+            id: id.clone(),
+            path,
+            columns: self.columns.clone(),
+            row_group_size: self.row_group_size,
+            schema: None,
+        };
+        // The parquet writer is blocking, so each file gets a thread of its own
+        let handle = runtime.spawn_blocking(move || writer.run(rx));
+        Sink::new(id, handle, tx)
+    }
+
+    fn get_sink_id(&self, event: &Value) -> String {
+        split_sink_id(self.split_key.as_ref(), event, &self.default)
+    }
+}
+
+struct ParquetWriter {
+    id: String,
+    path: PathBuf,
+    columns: Vec<Column>,
+    row_group_size: usize,
+    schema: Option<SchemaRef>,
+}
+
+impl ParquetWriter {
+    fn run(mut self, mut recv: mpsc::Receiver<SinkMessage>) -> Result<(), SinkError> {
+        info!("Started writing to parquet file: {}", self.path.display());
+        let mut writer = None;
+        let mut rows = Vec::with_capacity(self.row_group_size);
+
+        while let Some(msg) = recv.blocking_recv() {
+            match msg {
+                SinkMessage::New(event) => rows.push(event),
+            }
+            if rows.len() >= self.row_group_size {
+                self.write_row_group(&mut writer, &rows)?;
+                rows.clear();
+            }
+        }
+        if !rows.is_empty() {
+            self.write_row_group(&mut writer, &rows)?;
+        }
+
+        if let Some(writer) = writer {
+            writer.close().map_err(|e| self.error(e))?;
+        }
+        info!("Finished writing to parquet file: {}", self.path.display());
+        Ok(())
+    }
+
+    /// Write the rows out as a single row group, creating the file if this is the first one
+    fn write_row_group(
+        &mut self,
+        writer: &mut Option<ArrowWriter<File>>,
+        rows: &[Value],
+    ) -> Result<(), SinkError> {
+        let schema = match &self.schema {
+            Some(schema) => schema.clone(),
+            None => self.schema(rows),
+        };
+        let writer = match writer {
+            Some(writer) => writer,
+            None => writer.insert(self.open(schema.clone())?),
+        };
+        let batch = self.batch(schema, rows)?;
+        writer.write(&batch).map_err(|e| self.error(e))?;
+        writer.flush().map_err(|e| self.error(e))?;
+        debug!("Wrote {} rows to {}", rows.len(), self.path.display());
+        Ok(())
+    }
+
+    /// Fill in the types of any untyped columns from the first row group
+    fn schema(&mut self, rows: &[Value]) -> SchemaRef {
+        let mut fields: Vec<Field> = self
+            .columns
+            .iter_mut()
+            .map(|column| {
+                let kind = *column.kind.get_or_insert_with(|| {
+                    let values: Vec<_> = rows.iter().filter_map(|r| column.key.get(r)).collect();
+                    ColumnType::infer(&values)
+                });
+                Field::new(&column.name, data_type(kind), true)
+            })
+            .collect();
+        fields.push(Field::new(RAW_COLUMN, DataType::Utf8, false));
+        self.schema.insert(Arc::new(Schema::new(fields))).clone()
+    }
+
+    fn open(&self, schema: SchemaRef) -> Result<ArrowWriter<File>, SinkError> {
+        let file = File::create(&self.path)
+            .map_err(|e| SinkError::io(self.id.as_str(), Some(self.path.clone()), e))?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(self.row_group_size)
+            .build();
+        ArrowWriter::try_new(file, schema, Some(props)).map_err(|e| self.error(e))
+    }
+
+    fn batch(&self, schema: SchemaRef, rows: &[Value]) -> Result<RecordBatch, SinkError> {
+        let mut arrays: Vec<ArrayRef> = self
+            .columns
+            .iter()
+            .map(|column| {
+                let values = rows.iter().map(|r| column.key.get(r));
+                // Types are always filled in by the time the schema has been built
+                build_array(column.kind.unwrap_or(ColumnType::String), values)
+            })
+            .collect();
+        let mut raw = StringBuilder::new();
+        for row in rows {
+            raw.append_value(row.to_string());
+        }
+        arrays.push(Arc::new(raw.finish()));
+        RecordBatch::try_new(schema, arrays).map_err(|e| self.error(e.into()))
+    }
+
+    fn error(&self, e: parquet::errors::ParquetError) -> SinkError {
+        SinkError::io(
+            self.id.as_str(),
+            Some(self.path.clone()),
+            std::io::Error::other(e),
+        )
+    }
+}
+
+fn data_type(kind: ColumnType) -> DataType {
+    match kind {
+        ColumnType::String => DataType::Utf8,
+        ColumnType::Int => DataType::Int64,
+        ColumnType::Float => DataType::Float64,
+        ColumnType::Bool => DataType::Boolean,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+    }
+}
+
+// Values that don't fit the column type are written as nulls, rather than failing the whole file
+fn build_array(kind: ColumnType, values: impl Iterator<Item = Option<Value>>) -> ArrayRef {
+    match kind {
+        ColumnType::String => {
+            let mut builder = StringBuilder::new();
+            for value in values {
+                match value {
+                    None | Some(Value::Null) => builder.append_null(),
+                    Some(Value::String(s)) => builder.append_value(s),
+                    Some(other) => builder.append_value(other.to_string()),
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Int => {
+            let mut builder = Int64Builder::new();
+            for value in values {
+                builder.append_option(value.and_then(|v| {
+                    v.as_i64()
+                        .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+                }));
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Float => {
+            let mut builder = Float64Builder::new();
+            for value in values {
+                builder.append_option(value.and_then(|v| {
+                    v.as_f64()
+                        .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+                }));
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Bool => {
+            let mut builder = BooleanBuilder::new();
+            for value in values {
+                builder.append_option(value.and_then(|v| {
+                    v.as_bool()
+                        .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+                }));
+            }
+            Arc::new(builder.finish())
+        }
+        ColumnType::Timestamp => {
+            let mut builder = TimestampMicrosecondBuilder::new().with_timezone("UTC");
+            for value in values {
+                builder.append_option(
+                    value
+                        .and_then(|v| time::parse_timestamp(&v))
+                        .map(|t| t.timestamp_micros()),
+                );
+            }
+            Arc::new(builder.finish())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(names: &[&str]) -> Vec<Column> {
+        names
+            .iter()
+            .map(|name| Column::new(name.to_string(), JsonKey::from("attributes.x"), None))
+            .collect()
+    }
+
+    fn sink_set(names: &[&str]) -> Result<ParquetSinkSet, Error> {
+        ParquetSinkSet::new(None, "out".to_string(), columns(names), 100)
+    }
+
+    #[test]
+    fn rejects_columns_named_raw() {
+        for name in ["raw", "RAW"] {
+            assert!(
+                matches!(sink_set(&[name]), Err(Error::Invalid(_))),
+                "{}",
+                name
+            );
+        }
+        assert!(sink_set(&["raw_message"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_duplicate_columns() {
+        assert!(matches!(sink_set(&["pod", "Pod"]), Err(Error::Invalid(_))));
+        assert!(sink_set(&["pod", "node"]).is_ok());
+    }
+
+    #[test]
+    fn adds_the_raw_column_to_the_schema() {
+        let sink_set = sink_set(&["pod"]).unwrap();
+        let mut writer = ParquetWriter {
+            id: "out".to_string(),
+            path: PathBuf::from("out.parquet"),
+            columns: sink_set.columns,
+            row_group_size: 100,
+            schema: None,
+        };
+        let schema = writer.schema(&[serde_json::json!({"attributes": {"x": "a"}})]);
+        let names: Vec<_> = schema.fields().iter().map(|f| f.name().as_str()).collect();
+        assert_eq!(names, ["pod", "raw"]);
+    }
+}
@@ -0,0 +1,370 @@
+use std::path::PathBuf;
+
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection};
+use serde_json::Value;
+use tokio::{runtime, sync::mpsc};
+use tracing::{debug, info};
+
+use super::{Sink, SinkError, SinkMessage, SinkSet};
+use crate::{Column, ColumnType, Error, JsonKey};
+
+/// The sink id every event is dispatched to - there's only ever one writer, since sqlite only
+/// allows one at a time anyway. The split key is stored as an indexed column instead.
+const SINK_ID: &str = "sqlite";
+
+/// The columns every events table has, in the order they're inserted
+const BUILTIN_COLUMNS: [&str; 8] = [
+    "id",
+    "timestamp",
+    "status",
+    "service",
+    "host",
+    "message",
+    "split",
+    "raw",
+];
+
+/// A [SinkSet] that writes events into a sqlite database, so they can be queried with SQL.
+///
+/// Events go into an `events` table, with columns extracted for the id, timestamp, status,
+/// service, host, message and split key, any extra configured columns, and the raw event json.
+/// Inserts are batched into transactions, and events already in the table are skipped, so
+/// it's safe to point several runs at the same database.
+pub struct SqliteSinkSet {
+    path: PathBuf,
+    split_key: Option<JsonKey>,
+    columns: Vec<Column>,
+    batch_size: usize,
+}
+
+impl SqliteSinkSet {
+    /// Open (or create) the database at `path`, and make sure the events table and its indexes
+    /// exist. At most `batch_size` events are written per transaction. Extra columns can't share
+    /// a name with a built-in column, or each other
+    pub fn open(
+        path: PathBuf,
+        split_key: Option<JsonKey>,
+        columns: Vec<Column>,
+        batch_size: usize,
+    ) -> Result<Self, Error> {
+        // Sqlite column names are case insensitive
+        let mut names: Vec<String> = BUILTIN_COLUMNS.iter().map(|s| s.to_string()).collect();
+        for column in &columns {
+            let name = column.name.to_lowercase();
+            if BUILTIN_COLUMNS.contains(&name.as_str()) {
+                return Err(Error::Invalid(format!(
+                    "Column {} clashes with a built-in column of the events table, which are {}",
+                    column.name,
+                    BUILTIN_COLUMNS.join(", ")
+                )));
+            }
+            if names.contains(&name) {
+                return Err(Error::Invalid(format!(
+                    "Column {} is given more than once",
+                    column.name
+                )));
+            }
+            names.push(name);
+        }
+
+        let sink_set = SqliteSinkSet {
+            path,
+            split_key,
+            columns,
+            batch_size: batch_size.max(1),
+        };
+        sink_set.connect()?;
+        Ok(sink_set)
+    }
+
+    fn connect(&self) -> Result<Connection, SinkError> {
+        let conn = Connection::open(&self.path).map_err(|e| self.error(e))?;
+
+        let extra: String = self
+            .columns
+            .iter()
+            .map(|c| format!("{} {}, ", quote(&c.name), affinity(c.kind)))
+            .collect();
+        let schema = format!(
+            "CREATE TABLE IF NOT EXISTS events (
+                id TEXT PRIMARY KEY,
+                timestamp TEXT,
+                status TEXT,
+                service TEXT,
+                host TEXT,
+                message TEXT,
+                split TEXT,
+                {}raw TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS events_timestamp ON events (timestamp);
+            CREATE INDEX IF NOT EXISTS events_split ON events (split);",
+            extra
+        );
+        conn.execute_batch(&schema).map_err(|e| self.error(e))?;
+
+        // The table may have been created by a run with different extra columns
+        let existing: Vec<String> = conn
+            .prepare("SELECT name FROM pragma_table_info('events')")
+            .and_then(|mut s| s.query_map([], |r| r.get(0))?.collect())
+            .map_err(|e| self.error(e))?;
+        for column in &self.columns {
+            if !existing
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(&column.name))
+            {
+                debug!("Adding column {} to events table", column.name);
+                conn.execute_batch(&format!(
+                    "ALTER TABLE events ADD COLUMN {} {}",
+                    quote(&column.name),
+                    affinity(column.kind)
+                ))
+                .map_err(|e| self.error(e))?;
+            }
+        }
+
+        Ok(conn)
+    }
+
+    fn insert_statement(&self) -> String {
+        let mut names: Vec<String> = BUILTIN_COLUMNS.iter().map(|s| s.to_string()).collect();
+        names.extend(self.columns.iter().map(|c| quote(&c.name)));
+        let params = vec!["?"; names.len()].join(", ");
+        format!(
+            "INSERT OR IGNORE INTO events ({}) VALUES ({})",
+            names.join(", "),
+            params
+        )
+    }
+
+    fn row(&self, event: &Value) -> Vec<SqlValue> {
+        let attributes = &event["attributes"];
+        let sql = |v: &Value| match v {
+            Value::Null => SqlValue::Null,
+            Value::String(s) => SqlValue::Text(s.clone()),
+            Value::Bool(b) => SqlValue::Integer(*b as i64),
+            Value::Number(n) => n
+                .as_i64()
+                .map(SqlValue::Integer)
+                .or(n.as_f64().map(SqlValue::Real))
+                .unwrap_or(SqlValue::Text(n.to_string())),
+            other => SqlValue::Text(other.to_string()),
+        };
+        let mut row = vec![
+            sql(&event["id"]),
+            sql(&attributes["timestamp"]),
+            sql(&attributes["status"]),
+            sql(&attributes["service"]),
+            sql(&attributes["host"]),
+            sql(&attributes["message"]),
+            self.split_key
+                .as_ref()
+                .and_then(|k| k.get(event))
+                .map_or(SqlValue::Null, |v| sql(&v)),
+            SqlValue::Text(event.to_string()),
+        ];
+        row.extend(
+            self.columns
+                .iter()
+                .map(|c| c.key.get(event).map_or(SqlValue::Null, |v| sql(&v))),
+        );
+        row
+    }
+
+    fn error(&self, e: rusqlite::Error) -> SinkError {
+        SinkError::io(SINK_ID, Some(self.path.clone()), std::io::Error::other(e))
+    }
+}
+
+impl SinkSet for SqliteSinkSet {
+    fn construct_output(&self, _event: &Value, runtime: &runtime::Handle) -> Sink {
+        let (tx, rx) = mpsc::channel(self.batch_size);
+        let writer = SqliteSinkSet {
+            path: self.path.clone(),
+            split_key: self.split_key.clone(),
+            columns: self.columns.clone(),
+            batch_size: self.batch_size,
+        };
+        // Sqlite is blocking, so the writer gets a thread of its own
+        let handle = runtime.spawn_blocking(move || sqlite_writer(writer, rx));
+        Sink::new(SINK_ID.to_string(), handle, tx)
+    }
+
+    fn get_sink_id(&self, _event: &Value) -> String {
+        SINK_ID.to_string()
+    }
+}
+
+fn sqlite_writer(
+    writer: SqliteSinkSet,
+    mut recv: mpsc::Receiver<SinkMessage>,
+) -> Result<(), SinkError> {
+    info!("Started writing to database: {}", writer.path.display());
+    let mut conn = writer.connect()?;
+    let insert = writer.insert_statement();
+
+    let mut batch = Vec::with_capacity(writer.batch_size);
+    while let Some(msg) = recv.blocking_recv() {
+        batch.push(msg);
+        // Take whatever else has queued up while we were busy
+        while batch.len() < writer.batch_size {
+            match recv.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+
+        let tx = conn.transaction().map_err(|e| writer.error(e))?;
+        {
+            let mut statement = tx.prepare_cached(&insert).map_err(|e| writer.error(e))?;
+            for msg in batch.drain(..) {
+                match msg {
+                    SinkMessage::New(event) => {
+                        statement
+                            .execute(params_from_iter(writer.row(&event)))
+                            .map_err(|e| writer.error(e))?;
+                    }
+                }
+            }
+        }
+        tx.commit().map_err(|e| writer.error(e))?;
+    }
+    info!("Finished writing to database: {}", writer.path.display());
+    Ok(())
+}
+
+// Sqlite is dynamically typed, so this only guides how values are stored and compared
+fn affinity(kind: Option<ColumnType>) -> &'static str {
+    match kind {
+        Some(ColumnType::Int) | Some(ColumnType::Bool) => "INTEGER",
+        Some(ColumnType::Float) => "REAL",
+        Some(ColumnType::String) | Some(ColumnType::Timestamp) => "TEXT",
+        None => "",
+    }
+}
+
+fn quote(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn temp_db(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("dogtail-test-{}-{}.db", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn columns(names: &[&str]) -> Vec<Column> {
+        names
+            .iter()
+            .map(|name| Column::new(name.to_string(), JsonKey::from("attributes.x"), None))
+            .collect()
+    }
+
+    fn open(names: &[&str]) -> Result<SqliteSinkSet, Error> {
+        SqliteSinkSet::open(temp_db("columns"), None, columns(names), 10)
+    }
+
+    fn write(sink_set: SqliteSinkSet, events: Vec<Value>) {
+        let (tx, rx) = mpsc::channel(events.len());
+        for event in events {
+            tx.try_send(SinkMessage::New(event)).unwrap();
+        }
+        drop(tx);
+        sqlite_writer(sink_set, rx).unwrap();
+    }
+
+    fn query<T: rusqlite::types::FromSql>(path: &PathBuf, sql: &str) -> Vec<T> {
+        let conn = Connection::open(path).unwrap();
+        let mut statement = conn.prepare(sql).unwrap();
+        let rows = statement.query_map([], |r| r.get(0)).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn inserts_events_once_each() {
+        let path = temp_db("insert");
+        let event = |id: &str, x: i64| json!({"id": id, "attributes": {"service": "api", "team": "a", "x": x}});
+        let sink_set = || {
+            let columns = columns(&["x"]);
+            SqliteSinkSet::open(
+                path.clone(),
+                Some(JsonKey::from("attributes.team")),
+                columns,
+                2,
+            )
+            .unwrap()
+        };
+
+        // Five events, so the last batch isn't full, and a repeat that's ignored
+        let events = vec![
+            event("a", 1),
+            event("b", 2),
+            event("a", 3),
+            event("c", 4),
+            event("d", 5),
+        ];
+        write(sink_set(), events);
+        // Another run over the same events only adds the new one
+        write(sink_set(), vec![event("d", 6), event("e", 7)]);
+
+        let ids: Vec<String> = query(&path, "SELECT id FROM events ORDER BY id");
+        assert_eq!(ids, ["a", "b", "c", "d", "e"]);
+        let xs: Vec<i64> = query(&path, "SELECT x FROM events ORDER BY id");
+        assert_eq!(xs, [1, 2, 4, 5, 7]);
+        let splits: Vec<String> = query(&path, "SELECT DISTINCT split FROM events");
+        assert_eq!(splits, ["a"]);
+        let raw: Vec<String> = query(&path, "SELECT raw FROM events WHERE id = 'b'");
+        assert_eq!(
+            serde_json::from_str::<Value>(&raw[0]).unwrap(),
+            event("b", 2)
+        );
+
+        let indexes: Vec<String> = query(
+            &path,
+            "SELECT name FROM pragma_index_list('events') WHERE origin = 'c' ORDER BY name",
+        );
+        assert_eq!(indexes, ["events_split", "events_timestamp"]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn adds_new_columns_to_existing_tables() {
+        let path = temp_db("alter");
+        SqliteSinkSet::open(path.clone(), None, columns(&["Pod"]), 10).unwrap();
+        // Names that only differ in case are already there
+        SqliteSinkSet::open(path.clone(), None, columns(&["pod", "node"]), 10).unwrap();
+
+        let names: Vec<String> = query(&path, "SELECT name FROM pragma_table_info('events')");
+        assert_eq!(
+            names
+                .iter()
+                .filter(|n| n.eq_ignore_ascii_case("pod"))
+                .count(),
+            1
+        );
+        assert!(names.contains(&"node".to_string()));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_columns_named_like_builtin_ones() {
+        for name in ["host", "timestamp", "raw", "split", "ID"] {
+            let err = open(&[name]).err().expect("Reserved name was accepted");
+            assert!(err.to_string().contains("built-in column"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_repeated_columns() {
+        let err = open(&["pod", "Pod"])
+            .err()
+            .expect("Repeated name was accepted");
+        assert!(err.to_string().contains("more than once"), "{}", err);
+    }
+}
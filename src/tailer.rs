@@ -1,233 +1,641 @@
-use std::time::{Duration, Instant};
-
-use reqwest::{RequestBuilder, Response};
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt,
+};
+use reqwest::StatusCode;
 use serde_json::Value;
 use tokio::sync::mpsc::{self, Receiver};
-use tracing::{debug, info, instrument, warn};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::{
+    logs::LogEvent,
+    ratelimit::{RateLimitBudget, RateLimitCoordinator, RateLimitStatus},
+    transport::{HttpTransport, Request, Response, Transport},
+    Error, Source,
+};
 
-use crate::Source;
+/// How long to wait after a 429 that doesn't say when we can try again
+const RATE_LIMITED_WAIT: Duration = Duration::from_secs(10);
 
 /// The Tailer handles authentication, rate limiting, and pagination for a given source,
-/// and returns a receiver that will emit events as they are received. This lets you only
-/// worry about implementing the Source. The Tailer assumes the datadog rate-limit headers
-/// are present, and will scale how long it waits between requests based on 1) not exceeding
-/// the rate limit, and 2) how many useful results it got from the last request.
+/// and emits events as they are received, either as a stream or through a receiver. This lets
+/// you only worry about implementing the Source. The Tailer assumes the datadog rate-limit
+/// headers are present, and will scale how long it waits between requests based on 1) not
+/// exceeding the rate limit, and 2) how many useful results it got from the last request.
+///
+/// A Tailer can also run several named sources at once. Each is paced on its own, so a busy
+/// query isn't held back by a quiet one, while a shared [RateLimitBudget] keeps them inside the
+/// rate limit together. Their events are tagged with the source's name under "query".
 pub struct Tailer {
-    source: Box<dyn Source>,
-    client: reqwest::Client,
+    queries: Vec<Query>,
+    transport: Arc<dyn Transport>,
     api_key: String,
     app_key: String,
+    coordinator: Option<Arc<dyn RateLimitCoordinator>>,
+    run: Option<Run>,
+    /// Events from the last page that haven't been handed out yet
+    pending: VecDeque<Value>,
+}
+
+struct Query {
+    name: Option<String>,
+    source: Box<dyn Source>,
     last_limit_stats: Option<RateLimitStatus>,
 }
 
+/// A query being fetched, which can take several requests if the results are paginated
+struct Run {
+    query: usize,
+    /// The next request to make, or None once the last page has been fetched
+    request: Option<Request>,
+    returned: usize,
+}
+
 impl Tailer {
     /// Construct a tailer from a source, and the necessary API keys.
     pub fn new(api_key: String, app_key: String, source: Box<dyn Source>) -> Self {
+        Self::from_queries(
+            api_key,
+            app_key,
+            vec![Query {
+                name: None,
+                source,
+                last_limit_stats: None,
+            }],
+            None,
+        )
+    }
+
+    /// Construct a tailer that runs several named sources, sharing one rate limit between them.
+    /// Each event is tagged with the name of the source it came from, as a top-level "query" field
+    pub fn with_sources(
+        api_key: String,
+        app_key: String,
+        sources: Vec<(String, Box<dyn Source>)>,
+    ) -> Self {
+        let queries = sources
+            .into_iter()
+            .map(|(name, source)| Query {
+                name: Some(name),
+                source,
+                last_limit_stats: None,
+            })
+            .collect();
+        Self::from_queries(
+            api_key,
+            app_key,
+            queries,
+            Some(Arc::new(RateLimitBudget::new())),
+        )
+    }
+
+    fn from_queries(
+        api_key: String,
+        app_key: String,
+        queries: Vec<Query>,
+        coordinator: Option<Arc<dyn RateLimitCoordinator>>,
+    ) -> Self {
         Tailer {
-            source,
-            client: reqwest::Client::new(),
+            queries,
+            transport: Arc::new(HttpTransport::default()),
             api_key,
             app_key,
-            last_limit_stats: None,
+            coordinator,
+            run: None,
+            pending: VecDeque::new(),
         }
     }
 
+    /// Draw requests from a budget shared with other tailers, so that together they stay
+    /// inside the rate limit, rather than each assuming they have it all to themselves
+    pub fn with_coordinator(mut self, coordinator: impl RateLimitCoordinator + 'static) -> Self {
+        self.coordinator = Some(Arc::new(coordinator));
+        self
+    }
+
+    /// Send requests through this transport rather than with a default http client, e.g. to use
+    /// a client configured with a proxy, to record responses or replay them, or to answer with
+    /// canned responses in tests. It can be shared with other tailers
+    pub fn with_transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = transport;
+        self
+    }
+
     /// Start tailing from the passed source, returning a receiver that will emit
-    /// events as they are received
+    /// events as they are received. This drives [Tailer::into_stream] on a spawned task,
+    /// logging the error that stops it, if any
     pub async fn start(self) -> Receiver<Value> {
         let (send, recv) = mpsc::channel(100);
         tokio::spawn(async move {
-            let mut _self = self; // "But we can open the box" said toad. "That's true" said frog.
-            _self.tail(send).await;
+            let mut events = self.into_stream();
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) => {
+                        // The consumer going away is just how we're told to stop
+                        if send.send(event).await.is_err() {
+                            return;
+                        }
+                    }
+                    // There's nobody to hand errors to. We log them, so things like an
+                    // unreachable proxy aren't a mystery, and dropping the sender signals to
+                    // the consumer that we're done
+                    Err(e) => error!("Stopping: {}", e),
+                }
+            }
         });
         recv
     }
 
-    #[instrument(level = "debug", skip_all)]
-    async fn tail(&mut self, event_sink: mpsc::Sender<Value>) {
-        // We just eat the errors here, since we've been tokio::spawn'd. Dropping
-        // the sender should be enough to signal to consumer that we're done
-        while let Ok(Some(count)) = self.run_query(&event_sink).await {
-            info!("Returned {} events", count);
-            let seconds_to_next_call = self
-                .last_limit_stats
-                .as_ref()
-                .map(|l| {
-                    l.next_request_allowed
-                        .duration_since(Instant::now())
-                        .as_secs()
-                })
-                .unwrap_or(0);
-
-            info!("Waiting {}s", seconds_to_next_call);
-        }
+    /// Tail as a stream of events. Nothing runs in the background: requests are only made
+    /// while the stream is being polled and has no events left from the last one, so a slow
+    /// consumer slows the tailer down rather than events piling up, and dropping the stream
+    /// stops it, cancelling any request in flight. The stream ends after the first error, once
+    /// every source has returned None from [Source::construct_query], or once a replay has run
+    /// out of responses
+    pub fn into_stream(self) -> BoxStream<'static, Result<Value, Error>> {
+        stream::unfold(Some(self), |tailer| async move {
+            let mut tailer = tailer?;
+            match tailer.next_event().await {
+                Ok(Some(event)) => Some((Ok(event), Some(tailer))),
+                Ok(None) => None,
+                Err(Error::Exhausted) => {
+                    info!("Replayed every recorded response, stopping");
+                    None
+                }
+                Err(e) => {
+                    let e = match tailer.run.as_ref() {
+                        Some(run) => e.with_source_id(tailer.queries[run.query].name.as_deref()),
+                        None => e,
+                    };
+                    Some((Err(e), None))
+                }
+            }
+        })
+        .boxed()
     }
 
-    fn headers(&self, builder: RequestBuilder) -> RequestBuilder {
-        builder
-            .header("Accept", "application/json")
-            .header("DD-API-KEY", &self.api_key)
-            .header("DD-APPLICATION-KEY", &self.app_key)
+    /// As [Tailer::into_stream], for a tailer of [crate::logs::LogSource]s, with each event read
+    /// into a [LogEvent]
+    pub fn into_log_events(self) -> BoxStream<'static, Result<LogEvent, Error>> {
+        self.into_stream()
+            .map(|event| event.and_then(LogEvent::from_json))
+            .boxed()
     }
 
     #[instrument(level = "debug", skip_all)]
-    async fn send(&mut self, q: RequestBuilder) -> Result<Response, anyhow::Error> {
-        if let Some(limit_stats) = self.last_limit_stats.take() {
-            limit_stats.pause().await;
+    async fn next_event(&mut self) -> Result<Option<Value>, Error> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(Some(event));
+            }
+
+            let Some(run) = self.run.as_mut() else {
+                let Some(i) = self.next_query() else {
+                    return Ok(None);
+                };
+                match self.queries[i].source.construct_query() {
+                    Some(request) => {
+                        self.run = Some(Run {
+                            query: i,
+                            request: Some(request),
+                            returned: 0,
+                        })
+                    }
+                    None => {
+                        match &self.queries[i].name {
+                            Some(name) => {
+                                info!("Source for query {} returned None, stopping it", name)
+                            }
+                            None => info!("Source returned None, stopping"),
+                        }
+                        self.queries.remove(i);
+                    }
+                }
+                continue;
+            };
+
+            let Some(request) = run.request.take() else {
+                let run = self.run.take().expect("Run was just checked");
+                self.finish_run(run);
+                continue;
+            };
+            let i = run.query;
+
+            let response = self.send(i, request.clone()).await?;
+            if !response.status.is_success() {
+                self.handle_error(response)?;
+                // We have the correct interval period, so we can just wait and then re-request
+                // the same page
+                if let Some(run) = self.run.as_mut() {
+                    run.request = Some(request);
+                }
+                continue;
+            }
+
+            let body = response.json()?;
+            let query = &mut self.queries[i];
+            let next = query.source.extract_next(&body)?;
+            let results = query.source.extract_results(body)?;
+
+            if let Some(s) = query.last_limit_stats.as_mut() {
+                s.scale_remaining_by(results.len(), query.source.get_batch_size());
+            }
+
+            let run = self.run.as_mut().expect("Run is in progress");
+            run.returned += results.len();
+            run.request = next.map(|next_url| {
+                debug!("Following next link: {}", next_url);
+                Request::get(next_url)
+            });
+            self.pending
+                .extend(results.into_iter().map(|v| self.queries[i].tag(v)));
         }
-        let response = q.send().await?;
-        self.last_limit_stats = Some(RateLimitStatus::from(&response));
-        Ok(response)
+    }
+
+    fn finish_run(&mut self, run: Run) {
+        let query = &mut self.queries[run.query];
+        if !query.source.caught_up() {
+            // There's definitely more to fetch, so treat this like a page full of useful results
+            let batch_size = query.source.get_batch_size();
+            if let Some(s) = query.last_limit_stats.as_mut() {
+                s.scale_remaining_by(batch_size, batch_size);
+            }
+        }
+
+        match &query.name {
+            Some(name) => info!("Query {} returned {} events", name, run.returned),
+            None => info!("Returned {} events", run.returned),
+        }
+        let seconds_to_next_call = query
+            .last_limit_stats
+            .as_ref()
+            .map(|l| {
+                l.next_request_allowed
+                    .duration_since(Instant::now())
+                    .as_secs()
+            })
+            .unwrap_or(0);
+
+        info!("Waiting {}s", seconds_to_next_call);
+    }
+
+    /// The query that's allowed to make its next request soonest. Ties go to the first, and
+    /// since running a query pushes its next request back, this round-robins between queries
+    /// that are all ready to go
+    fn next_query(&self) -> Option<usize> {
+        self.queries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, q)| q.last_limit_stats.as_ref().map(|l| l.next_request_allowed))
+            .map(|(i, _)| i)
+    }
+
+    fn headers(&self, request: Request) -> Result<Request, Error> {
+        request
+            .header("Accept", "application/json")?
+            .secret_header("DD-API-KEY", &self.api_key)?
+            .secret_header("DD-APPLICATION-KEY", &self.app_key)
     }
 
     #[instrument(level = "debug", skip_all)]
-    async fn run_query(
-        &mut self,
-        event_sink: &mpsc::Sender<Value>,
-    ) -> Result<Option<usize>, anyhow::Error> {
-        let Some(req) = self.source.construct_query(&self.client) else {
-            info!("Source returned None, stopping");
-            return Ok(None);
+    async fn send(&mut self, i: usize, q: Request) -> Result<Response, Error> {
+        let last_limit_stats = self.queries[i].last_limit_stats.take();
+        let paced = self.transport.paced();
+        if paced {
+            if let Some(limit_stats) = last_limit_stats {
+                limit_stats.pause().await;
+            }
+            if let Some(coordinator) = &self.coordinator {
+                coordinator.acquire().await;
+            }
+        }
+        let response = self.transport.execute(self.headers(q)?).await?;
+        let status = match RateLimitStatus::try_from(&response) {
+            Ok(status) => status,
+            // Errors from something in front of the API, e.g. a proxy or load balancer, don't
+            // have rate-limit headers, and what went wrong matters more than their absence. If
+            // we're told to slow down without being told for how long, we wait a while
+            Err(_) if response.status == StatusCode::TOO_MANY_REQUESTS => {
+                RateLimitStatus::wait(RATE_LIMITED_WAIT)
+            }
+            Err(_) if !response.status.is_success() => return Ok(response),
+            Err(e) => return Err(e),
         };
+        if let Some(coordinator) = self.coordinator.as_ref().filter(|_| paced) {
+            coordinator.update(&status).await;
+        }
+        self.queries[i].last_limit_stats = Some(status);
+        Ok(response)
+    }
 
-        let first = self.send(self.headers(req)).await?;
+    fn handle_error(&self, response: Response) -> Result<(), Error> {
+        match Error::from_response(response.status, &response.body) {
+            Error::RateLimited { .. } => {
+                warn!("Got too_many_requests, waiting and retrying");
+                Ok(())
+            }
+            e => Err(e),
+        }
+    }
+}
 
-        if !first.status().is_success() {
-            return self.handle_error(first, 0).await.map(|o| Some(o));
+impl Query {
+    fn tag(&self, mut event: Value) -> Value {
+        if let (Some(name), Some(fields)) = (&self.name, event.as_object_mut()) {
+            fields.insert("query".to_string(), Value::String(name.clone()));
         }
+        event
+    }
+}
 
-        let body: Value = first.json().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        logs::{LogSource, Snapshot},
+        transport::{
+            fake::{response, with_rate_limit, FakeTransport},
+            Recorder, Replayer,
+        },
+    };
+    use chrono::Utc;
+    use serde_json::json;
+
+    /// Requests `url` a fixed number of times, returning the events under "data"
+    struct Repeat {
+        url: &'static str,
+        queries: usize,
+    }
 
-        let mut returned = 0;
+    impl Source for Repeat {
+        fn construct_query(&mut self) -> Option<Request> {
+            self.queries = self.queries.checked_sub(1)?;
+            Some(Request::get(self.url))
+        }
 
-        let mut next = self.source.extract_next(&body)?;
+        fn extract_results(&mut self, mut body: Value) -> Result<Vec<Value>, Error> {
+            Ok(body["data"]
+                .as_array_mut()
+                .map(std::mem::take)
+                .unwrap_or_default())
+        }
 
-        for v in self.source.extract_results(body)? {
-            returned += 1;
-            event_sink.send(v).await?;
+        fn get_batch_size(&mut self) -> usize {
+            1
         }
+    }
 
-        self.last_limit_stats.as_mut().map(|s| {
-            s.scale_remaining_by(returned, self.source.get_batch_size());
-        });
+    fn repeat(url: &'static str, queries: usize) -> Box<dyn Source> {
+        Box::new(Repeat { url, queries })
+    }
 
-        while let Some(next_url) = &next {
-            debug!("Following next link: {}", next_url);
-            let response = self.send(self.headers(self.client.get(next_url))).await?;
+    fn page(events: Value, next: Option<&str>) -> Response {
+        let body = match next {
+            Some(next) => json!({"data": events, "links": {"next": next}}),
+            None => json!({"data": events}),
+        };
+        with_rate_limit(response(200, body), 99, 1)
+    }
 
-            if !response.status().is_success() {
-                self.handle_error(response, returned).await?;
-                continue; // If we hit a 429 while following next links, we should just re-request that page
-            }
+    fn tailer(source: Box<dyn Source>, transport: &Arc<FakeTransport>) -> Tailer {
+        Tailer::new("api".to_string(), "app".to_string(), source).with_transport(transport.clone())
+    }
 
-            let body = response.json().await?;
+    async fn collect(tailer: Tailer) -> Vec<Result<Value, Error>> {
+        tailer.into_stream().collect().await
+    }
 
-            next = self.source.extract_next(&body)?;
+    #[tokio::test]
+    async fn follows_next_links() {
+        let transport = Arc::new(
+            FakeTransport::default()
+                .with_response("https://q", page(json!([{"id": 1}]), Some("https://q/2")))
+                .with_response("https://q/2", page(json!([{"id": 2}, {"id": 3}]), None)),
+        );
+        let events = collect(tailer(repeat("https://q", 1), &transport)).await;
 
-            let results = self.source.extract_results(body)?;
+        let events: Vec<_> = events.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            events,
+            [json!({"id": 1}), json!({"id": 2}), json!({"id": 3})]
+        );
+        assert_eq!(transport.urls(), ["https://q", "https://q/2"]);
+    }
 
-            self.last_limit_stats
-                .as_mut()
-                .map(|s| s.scale_remaining_by(results.len(), self.source.get_batch_size()));
+    #[tokio::test]
+    async fn retries_the_same_page_after_a_429() {
+        let transport = Arc::new(
+            FakeTransport::default()
+                .with_response("https://q", page(json!([{"id": 1}]), Some("https://q/2")))
+                .with_response(
+                    "https://q/2",
+                    with_rate_limit(response(429, json!({"errors": ["slow down"]})), 0, 1),
+                )
+                .with_response("https://q/2", response(429, json!({})))
+                .with_response("https://q/2", page(json!([{"id": 2}]), None)),
+        );
+        let events = collect(tailer(repeat("https://q", 1), &transport)).await;
 
-            for v in results {
-                returned += 1;
-                event_sink.send(v).await?;
-            }
-        }
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(Result::is_ok));
+        assert_eq!(
+            transport.urls(),
+            ["https://q", "https://q/2", "https://q/2", "https://q/2"]
+        );
+    }
 
-        Ok(Some(returned))
+    #[tokio::test]
+    async fn waits_after_a_429_without_rate_limit_headers() {
+        let transport =
+            Arc::new(FakeTransport::default().with_response("https://q", response(429, json!({}))));
+        let mut tailer = tailer(repeat("https://q", 1), &transport);
+
+        let sent = Instant::now();
+        let response = tailer.send(0, Request::get("https://q")).await.unwrap();
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        let status = tailer.queries[0].last_limit_stats.as_ref().unwrap();
+        assert!(status.next_request_allowed >= sent + RATE_LIMITED_WAIT);
+        assert!(status.next_request_allowed <= Instant::now() + RATE_LIMITED_WAIT);
     }
 
-    async fn handle_error(
-        &mut self,
-        response: Response,
-        returned_so_far: usize,
-    ) -> Result<usize, anyhow::Error> {
-        match response.status() {
-            reqwest::StatusCode::TOO_MANY_REQUESTS => {
-                warn!("Got too_many_requests, waiting and retrying");
-                Ok(returned_so_far) // We have the correct interval period, we can just wait and then retry
-            }
-            _ => Err(anyhow::anyhow!("Error: {}", response.text().await.unwrap())),
-        }
+    #[tokio::test]
+    async fn returns_errors_without_rate_limit_headers() {
+        let transport = Arc::new(
+            FakeTransport::default()
+                .with_response("https://q", response(502, json!("bad gateway")))
+                .with_response("https://ok", response(200, json!({"data": []}))),
+        );
+        let events = collect(tailer(repeat("https://q", 1), &transport)).await;
+        assert!(matches!(events[..], [Err(Error::Api { status, .. })] if status == 502));
+
+        // A successful response is expected to have them
+        let events = collect(tailer(repeat("https://ok", 1), &transport)).await;
+        assert!(matches!(events[..], [Err(Error::Malformed { .. })]));
     }
-}
 
-#[derive(Debug)]
-struct RateLimitStatus {
-    period: Duration,
-    remaining_budget: u32,
-    next_request_allowed: Instant,
-}
+    #[tokio::test]
+    async fn ends_the_stream_after_an_error() {
+        let transport = Arc::new(
+            FakeTransport::default()
+                .with_response("https://q", page(json!([{"id": 1}]), None))
+                .with_response(
+                    "https://q",
+                    with_rate_limit(response(500, json!({"errors": ["boom"]})), 99, 1),
+                )
+                .with_response("https://q", page(json!([{"id": 2}]), None)),
+        );
+        let events = collect(tailer(repeat("https://q", 3), &transport)).await;
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].as_ref().unwrap(), &json!({"id": 1}));
+        assert!(matches!(events[1], Err(Error::Api { status, .. }) if status == 500));
+        assert_eq!(transport.urls().len(), 2);
+    }
 
-impl From<&Response> for RateLimitStatus {
-    fn from(response: &Response) -> Self {
-        let get = |key: &str| response.headers().get(key).unwrap().to_str().unwrap();
+    #[tokio::test]
+    async fn ends_the_stream_when_a_replay_runs_out() {
+        let dir = std::env::temp_dir().join(format!("dogtail-{}-replay", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let inner = FakeTransport::default()
+            .with_response("https://q", page(json!([{"id": 1}]), None))
+            .with_response("https://q", page(json!([{"id": 2}]), None));
+        let recorder = Recorder::new(dir.clone(), Arc::new(inner)).unwrap();
+        let events = Tailer::new("api".to_string(), "app".to_string(), repeat("https://q", 2))
+            .with_transport(Arc::new(recorder))
+            .into_stream()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events.len(), 2);
+
+        // Following forever, but there's only so much recorded
+        let events = Tailer::new(
+            "api".to_string(),
+            "app".to_string(),
+            repeat("https://q", 100),
+        )
+        .with_transport(Arc::new(Replayer::new(&dir).unwrap()))
+        .into_stream()
+        .collect::<Vec<_>>()
+        .await;
+        let events: Vec<_> = events.into_iter().map(Result::unwrap).collect();
+        assert_eq!(events, [json!({"id": 1}), json!({"id": 2})]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-        // TODO - figure out a use for this in the wait time calculation
-        // let limit = get("x-ratelimit-limit").parse().unwrap();
-        let period = Duration::from_secs(
-            get("x-ratelimit-period")
-                .parse()
-                .expect("Header x-ratelimit-period not found"),
+    #[tokio::test]
+    async fn tags_events_with_their_query() {
+        let transport = Arc::new(
+            FakeTransport::default()
+                .with_response("https://a", page(json!([{"id": 1}, "not an object"]), None))
+                .with_response("https://b", page(json!([{"id": 2}]), None)),
+        );
+        let sources = vec![
+            ("a".to_string(), repeat("https://a", 1)),
+            ("b".to_string(), repeat("https://b", 1)),
+        ];
+        let tailer = Tailer::with_sources("api".to_string(), "app".to_string(), sources)
+            .with_transport(transport.clone());
+        let events: Vec<_> = collect(tailer)
+            .await
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+
+        assert_eq!(
+            events,
+            [
+                json!({"id": 1, "query": "a"}),
+                json!("not an object"),
+                json!({"id": 2, "query": "b"})
+            ]
         );
-        let remaining_budget = get("x-ratelimit-remaining")
-            .parse()
-            .expect("Header x-ratelimit-remaining not found");
-        let reset_time = Instant::now()
-            + Duration::from_secs(
-                get("x-ratelimit-reset")
-                    .parse()
-                    .expect("Header x-ratelimit-reset not found"),
-            );
-
-        let status = RateLimitStatus {
-            period,
-            remaining_budget,
-            next_request_allowed: reset_time,
-        };
-        debug!("Rate limit status: {:?}", status);
-        status
     }
-}
 
-// In order to be a good citizen, we always wait until reset + [0.0..5.0) seconds before requesting again
-// TODO - this is an antipattern - it should be impossible to make another request until the rate limit is reset
-impl RateLimitStatus {
-    async fn pause(&self) {
-        let wait = self.next_request_allowed.duration_since(Instant::now());
-        let jitter = Duration::from_secs_f32(rand::random::<f32>() * 5.0);
-        let wait = wait + jitter;
-        if wait > Duration::from_secs(0) {
-            debug!("Waiting {}s", wait.as_secs());
-            tokio::time::sleep(wait).await;
+    fn two_queries(a: usize, b: usize, transport: &Arc<FakeTransport>) -> Tailer {
+        let sources = vec![
+            ("a".to_string(), repeat("https://a", a)),
+            ("b".to_string(), repeat("https://b", b)),
+        ];
+        Tailer::with_sources("api".to_string(), "app".to_string(), sources)
+            .with_transport(transport.clone())
+    }
+
+    #[tokio::test]
+    async fn round_robins_between_busy_queries() {
+        let mut transport = FakeTransport::default();
+        for _ in 0..3 {
+            transport = transport
+                .with_response("https://a", page(json!([{"id": 1}]), None))
+                .with_response("https://b", page(json!([{"id": 2}]), None));
         }
+        let transport = Arc::new(transport);
+        let events = collect(two_queries(3, 3, &transport)).await;
+
+        assert_eq!(events.len(), 6);
+        assert_eq!(
+            transport.urls(),
+            [
+                "https://a",
+                "https://b",
+                "https://a",
+                "https://b",
+                "https://a",
+                "https://b"
+            ]
+        );
     }
 
-    // We scale how long we wait by portion of time period until next budget allocation and
-    // by how likely we are to get useful results from our query (basically, the last time we
-    // hit the server, how many useful result did we get).
-    fn scale_remaining_by(&mut self, returned: usize, limit: usize) {
-        let useful_results_factor = returned as f32 / limit as f32;
-        // The more useful results we got, the less of our budget period we want to wait
-        let desired_wait = self.period.as_millis() as f32 * (1.0 - useful_results_factor);
-
-        if self.remaining_budget > 0 {
-            let wait = desired_wait
-                .min(
-                    self.next_request_allowed
-                        .duration_since(Instant::now())
-                        .as_millis() as f32,
-                )
-                .max(0.0);
-            debug!(
-                "Scaled wait by {}, desired wait was {}ms, waiting {}ms",
-                useful_results_factor, desired_wait, wait
-            );
-            self.next_request_allowed = Instant::now() + Duration::from_millis(wait as u64);
-        } else {
-            debug!("No remaining budget, not scaling wait time");
+    #[tokio::test]
+    async fn keeps_quiet_queries_from_holding_back_busy_ones() {
+        // The quiet query's empty pages push its next request back to the reset, while the busy
+        // one's full pages let it go again straight away
+        let quiet = || with_rate_limit(response(200, json!({"data": []})), 99, 60);
+        let mut transport = FakeTransport::default()
+            .with_response("https://a", quiet())
+            .with_response("https://a", quiet());
+        for _ in 0..3 {
+            transport = transport.with_response("https://b", page(json!([{"id": 2}]), None));
         }
+        let transport = Arc::new(transport);
+        let events = collect(two_queries(2, 3, &transport)).await;
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            transport.urls(),
+            [
+                "https://a",
+                "https://b",
+                "https://b",
+                "https://b",
+                "https://a"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_keys_out_of_request_debug_output() {
+        let now = Utc::now();
+        let source = LogSource::new(
+            "example.com".to_string(),
+            "*".to_string(),
+            Snapshot::new(now, now),
+        );
+        let tailer = Tailer::new(
+            "secret-api-key".to_string(),
+            "secret-app-key".to_string(),
+            Box::new(source),
+        );
+        let request = tailer.headers(Request::get("https://example.com")).unwrap();
+
+        let debug = format!("{:?}", request);
+        assert!(debug.contains("dd-api-key"), "{}", debug);
+        assert!(!debug.contains("secret-api-key"), "{}", debug);
+        assert!(!debug.contains("secret-app-key"), "{}", debug);
     }
 }
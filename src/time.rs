@@ -0,0 +1,249 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde_json::Value;
+
+use crate::Error;
+
+/// Parse a timestamp as found in an event, i.e. an rfc3339 string. Anything else is None
+pub fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value.as_str()?)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// Parse a human-friendly point in time. Accepts:
+/// - rfc3339 timestamps, e.g. "2024-01-01T10:00:00Z"
+/// - "now", optionally offset by a duration, e.g. "now-2h", "now + 90m"
+/// - a bare offset from now, e.g. "-15m"
+/// - "today" or "yesterday", optionally with a time of day, e.g. "yesterday 09:00"
+/// - a time of day, taken to be today, e.g. "09:30"
+/// - a date, optionally with a time of day, e.g. "2024-01-01", "2024-01-01 10:00"
+///
+/// Anything without an explicit offset is in the local timezone
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, Error> {
+    parse_time_from(s, Local::now())
+}
+
+/// As [parse_time], but relative to `now` rather than the current time
+pub fn parse_time_from(s: &str, now: DateTime<Local>) -> Result<DateTime<Utc>, Error> {
+    let s = s.trim();
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.with_timezone(&Utc));
+    }
+
+    if let Some(rest) = s.strip_prefix("now") {
+        let rest = rest.trim();
+        if rest.is_empty() {
+            return Ok(now.with_timezone(&Utc));
+        }
+        return Ok((now + signed_duration(rest)?).with_timezone(&Utc));
+    }
+
+    if s.starts_with('-') || s.starts_with('+') {
+        return Ok((now + signed_duration(s)?).with_timezone(&Utc));
+    }
+
+    let (day, time) = match s.split_once(char::is_whitespace) {
+        Some((day, time)) => (day, Some(time.trim())),
+        None => (s, None),
+    };
+    let date = match day {
+        "today" => Some(now.date_naive()),
+        "yesterday" => Some(now.date_naive() - Duration::days(1)),
+        _ => NaiveDate::parse_from_str(day, "%Y-%m-%d").ok(),
+    };
+
+    let naive = match (date, time) {
+        (Some(date), None) => date.and_time(NaiveTime::MIN),
+        (Some(date), Some(time)) => date.and_time(parse_time_of_day(time)?),
+        (None, None) => match parse_time_of_day(s) {
+            Ok(time) => now.date_naive().and_time(time),
+            Err(_) => parse_naive_date_time(s)?,
+        },
+        (None, Some(_)) => parse_naive_date_time(s)?,
+    };
+
+    local(naive)
+}
+
+/// Parse a duration like "90m", "1h30m", "2d" or "45s". A bare number is taken as seconds
+pub fn parse_duration(s: &str) -> Result<Duration, Error> {
+    let s = s.trim();
+    if let Ok(seconds) = s.parse::<i64>() {
+        return Ok(Duration::seconds(seconds));
+    }
+
+    let mut total = Duration::zero();
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+        let n: i64 = number.parse().map_err(|_| {
+            Error::Invalid(format!(
+                "Invalid duration {}, expected e.g. 90m or 1h30m",
+                s
+            ))
+        })?;
+        number.clear();
+        total += match c {
+            's' => Duration::seconds(n),
+            'm' => Duration::minutes(n),
+            'h' => Duration::hours(n),
+            'd' => Duration::days(n),
+            'w' => Duration::weeks(n),
+            _ => {
+                return Err(Error::Invalid(format!(
+                    "Unknown duration unit {} in {}, expected one of s, m, h, d, w",
+                    c, s
+                )))
+            }
+        };
+    }
+    if !number.is_empty() || s.is_empty() {
+        return Err(Error::Invalid(format!(
+            "Invalid duration {}, expected e.g. 90m or 1h30m",
+            s
+        )));
+    }
+    Ok(total)
+}
+
+fn signed_duration(s: &str) -> Result<Duration, Error> {
+    let s = s.trim();
+    if let Some(rest) = s.strip_prefix('-') {
+        Ok(-parse_duration(rest)?)
+    } else if let Some(rest) = s.strip_prefix('+') {
+        parse_duration(rest)
+    } else {
+        Err(Error::Invalid(format!(
+            "Expected an offset like -2h or +15m, got {}",
+            s
+        )))
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Result<NaiveTime, Error> {
+    NaiveTime::parse_from_str(s, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(s, "%H:%M"))
+        .map_err(|_| Error::Invalid(format!("Invalid time of day {}, expected e.g. 09:00", s)))
+}
+
+fn parse_naive_date_time(s: &str) -> Result<NaiveDateTime, Error> {
+    [
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+    ]
+    .iter()
+    .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+    .ok_or(Error::Invalid(format!(
+        "Couldn't parse time {}, expected e.g. 2024-01-01T10:00:00Z, now-2h, or yesterday 09:00",
+        s
+    )))
+}
+
+fn local(naive: NaiveDateTime) -> Result<DateTime<Utc>, Error> {
+    // If the clocks went back, we take the first of the two, and if they went forward there's
+    // no such local time at all
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+        .ok_or(Error::Invalid(format!(
+            "{} doesn't exist in the local timezone",
+            naive
+        )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Mid-June, well away from any daylight saving changes, whatever the local timezone is
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 6, 15, 12, 30, 0).unwrap()
+    }
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Local
+            .with_ymd_and_hms(y, m, d, h, min, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_offsets_from_now() {
+        let now = now();
+        assert_eq!(
+            parse_time_from("now-2h", now).unwrap(),
+            now - Duration::hours(2)
+        );
+        assert_eq!(
+            parse_time_from("now + 90m", now).unwrap(),
+            now + Duration::minutes(90)
+        );
+        assert_eq!(
+            parse_time_from("-15m", now).unwrap(),
+            now - Duration::minutes(15)
+        );
+        assert_eq!(parse_time_from("now", now).unwrap(), now);
+    }
+
+    #[test]
+    fn parses_days_and_times_in_the_local_timezone() {
+        let now = now();
+        assert_eq!(
+            parse_time_from("yesterday 09:00", now).unwrap(),
+            local(2024, 6, 14, 9, 0)
+        );
+        assert_eq!(
+            parse_time_from("today", now).unwrap(),
+            local(2024, 6, 15, 0, 0)
+        );
+        assert_eq!(
+            parse_time_from("09:30", now).unwrap(),
+            local(2024, 6, 15, 9, 30)
+        );
+        assert_eq!(
+            parse_time_from("2024-01-01 10:00", now).unwrap(),
+            local(2024, 1, 1, 10, 0)
+        );
+        assert_eq!(
+            parse_time_from("2024-01-01T10:00:00Z", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_times_it_cant_parse() {
+        for s in [
+            "now-",
+            "now-1x",
+            "tomorrow",
+            "2024-13-01",
+            "yesterday 25:00",
+        ] {
+            assert!(parse_time_from(s, now()).is_err(), "{} was accepted", s);
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::minutes(90));
+        assert_eq!(parse_duration("90").unwrap(), Duration::seconds(90));
+        assert_eq!(parse_duration("2d").unwrap(), Duration::days(2));
+        assert_eq!(parse_duration("1m 30s").unwrap(), Duration::seconds(90));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for s in ["1x", "h", "", "1h30"] {
+            assert!(parse_duration(s).is_err(), "{:?} was accepted", s);
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::str::FromStr;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::{Error, JsonKey};
+
+/// A list of changes made to each event before it's written anywhere, e.g. to rename fields,
+/// drop noisy attributes, or pull structured fields out of the message.
+///
+/// A pipeline is written one step per line, and steps run in order. Blank lines and lines
+/// starting with # are ignored:
+///
+/// ```text
+/// # Move a field
+/// rename attributes.attributes.usr.id user_id
+/// # Remove fields, if they're there
+/// drop attributes.attributes.debug attributes.raw_tags
+/// # Parse a message that's json, in place or into another field
+/// parse-json attributes.message attributes.parsed
+/// # Copy the named groups of a regex that matches a field into an object
+/// extract attributes.message attributes.timing took (?P<ms>\d+)ms
+/// # Set a field from a template of other fields
+/// set origin {attributes.service}@{attributes.host}
+/// ```
+///
+/// Steps that don't apply to an event, e.g. because a field is missing or a regex doesn't
+/// match, leave it as it is
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+#[derive(Clone)]
+pub enum Step {
+    /// Move the value at `from` to `to`
+    Rename { from: JsonKey, to: JsonKey },
+    /// Remove these fields
+    Drop(Vec<JsonKey>),
+    /// Parse the string at `key` as json, writing it to `into`, or back to `key` if that's None.
+    /// Strings that aren't json are left alone
+    ParseJson { key: JsonKey, into: Option<JsonKey> },
+    /// Match `regex` against the string at `key`, writing each named group that matched to a
+    /// field of that name under `into`
+    Extract {
+        key: JsonKey,
+        into: JsonKey,
+        regex: Regex,
+    },
+    /// Set `key` to `template`, filled in from the event
+    Set { key: JsonKey, template: Template },
+}
+
+/// A string with other fields of the event filled in, e.g. "{attributes.service}@{attributes.host}".
+/// Braces can be escaped by doubling them. A template that's just one field copies that
+/// field's value as it is, rather than as a string
+#[derive(Clone)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone)]
+enum Part {
+    Literal(String),
+    Field(JsonKey),
+}
+
+impl Pipeline {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Pipeline { steps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Run every step over the event
+    pub fn apply(&self, event: &mut Value) {
+        for step in &self.steps {
+            step.apply(event);
+        }
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut steps = vec![];
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let step = line
+                .parse()
+                .map_err(|e| Error::Invalid(format!("line {}: {}", i + 1, e)))?;
+            steps.push(step);
+        }
+        Ok(Pipeline { steps })
+    }
+}
+
+impl Step {
+    pub fn apply(&self, event: &mut Value) {
+        match self {
+            Step::Rename { from, to } => {
+                if let Some(value) = from.remove(event) {
+                    // If something in the way of `to` isn't an object, the value stays put
+                    // rather than being lost
+                    if !to.set(event, value.clone()) {
+                        from.set(event, value);
+                    }
+                }
+            }
+            Step::Drop(keys) => {
+                for key in keys {
+                    key.remove(event);
+                }
+            }
+            Step::ParseJson { key, into } => {
+                let Some(Value::String(s)) = key.get(event) else {
+                    return;
+                };
+                if let Ok(parsed) = serde_json::from_str(&s) {
+                    into.as_ref().unwrap_or(key).set(event, parsed);
+                }
+            }
+            Step::Extract { key, into, regex } => {
+                let Some(Value::String(s)) = key.get(event) else {
+                    return;
+                };
+                let Some(captures) = regex.captures(&s) else {
+                    return;
+                };
+                let Some(Value::Object(mut fields)) = into
+                    .get(event)
+                    .or_else(|| Some(Value::Object(Default::default())))
+                else {
+                    return;
+                };
+                for name in regex.capture_names().flatten() {
+                    if let Some(m) = captures.name(name) {
+                        fields.insert(name.to_string(), Value::String(m.as_str().to_string()));
+                    }
+                }
+                into.set(event, Value::Object(fields));
+            }
+            Step::Set { key, template } => {
+                if let Some(value) = template.render(event) {
+                    key.set(event, value);
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Step {
+    type Err = Error;
+
+    /// Parse a step as written in a pipeline file. See [Pipeline]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |usage: &str| Error::Invalid(format!("Expected {}, got {}", usage, s));
+        let (name, rest) = s.trim().split_once(char::is_whitespace).unwrap_or((s, ""));
+        let rest = rest.trim();
+        let args: Vec<_> = rest.split_whitespace().collect();
+        match name {
+            "rename" => match args[..] {
+                [from, to] => Ok(Step::Rename {
+                    from: from.into(),
+                    to: to.into(),
+                }),
+                _ => Err(invalid("rename FROM TO")),
+            },
+            "drop" if !args.is_empty() => {
+                Ok(Step::Drop(args.into_iter().map(JsonKey::from).collect()))
+            }
+            "drop" => Err(invalid("drop KEY...")),
+            "parse-json" => match args[..] {
+                [key] => Ok(Step::ParseJson {
+                    key: key.into(),
+                    into: None,
+                }),
+                [key, into] => Ok(Step::ParseJson {
+                    key: key.into(),
+                    into: Some(into.into()),
+                }),
+                _ => Err(invalid("parse-json KEY [INTO]")),
+            },
+            "extract" => {
+                // The regex is the rest of the line, so it can have spaces in it
+                let mut parts = rest.splitn(3, char::is_whitespace);
+                let (Some(key), Some(into), Some(regex)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(invalid("extract KEY INTO REGEX"));
+                };
+                let regex = Regex::new(regex.trim())
+                    .map_err(|e| Error::Invalid(format!("Invalid regex in {}: {}", s, e)))?;
+                if regex.capture_names().flatten().next().is_none() {
+                    return Err(Error::Invalid(format!(
+                        "The regex in {} has no named groups, e.g. (?P<name>...), to extract",
+                        s
+                    )));
+                }
+                Ok(Step::Extract {
+                    key: key.into(),
+                    into: into.into(),
+                    regex,
+                })
+            }
+            "set" => match rest.split_once(char::is_whitespace) {
+                Some((key, template)) => Ok(Step::Set {
+                    key: key.into(),
+                    template: template.trim().parse()?,
+                }),
+                None => Err(invalid("set KEY TEMPLATE")),
+            },
+            _ => Err(Error::Invalid(format!(
+                "Unknown step {}, expected one of rename, drop, parse-json, extract, set",
+                name
+            ))),
+        }
+    }
+}
+
+impl Template {
+    /// Fill in the template from the event. If any of the fields it uses are missing, there's
+    /// nothing sensible to fill in, so this returns None
+    pub fn render(&self, event: &Value) -> Option<Value> {
+        if let [Part::Field(key)] = &self.parts[..] {
+            return key.get(event);
+        }
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => rendered.push_str(s),
+                Part::Field(key) => match key.get(event)? {
+                    Value::String(s) => rendered.push_str(&s),
+                    value => rendered.push_str(&value.to_string()),
+                },
+            }
+        }
+        Some(Value::String(rendered))
+    }
+}
+
+impl FromStr for Template {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut key = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => key.push(c),
+                            None => {
+                                return Err(Error::Invalid(format!(
+                                    "Unclosed {{ in template {}",
+                                    s
+                                )))
+                            }
+                        }
+                    }
+                    if key.trim().is_empty() {
+                        return Err(Error::Invalid(format!("Empty field in template {}", s)));
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(JsonKey::from(key.trim())));
+                }
+                '}' => {
+                    return Err(Error::Invalid(format!(
+                        "Unmatched }} in template {}, use }}}} for a literal one",
+                        s
+                    )))
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn fixture() -> Value {
+        json!({
+            "id": "a",
+            "attributes": {
+                "service": "api",
+                "host": "web-1",
+                "message": "GET /users took 35ms",
+                "attributes": {
+                    "usr": {"id": 42},
+                    "debug": true,
+                    "payload": "{\"user\": \"bob\", \"retries\": 2}"
+                }
+            }
+        })
+    }
+
+    fn apply(step: &str, mut event: Value) -> Value {
+        step.parse::<Step>().unwrap().apply(&mut event);
+        event
+    }
+
+    fn error(s: &str) -> String {
+        match s.parse::<Pipeline>() {
+            Ok(_) => panic!("{:?} was accepted", s),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn renames_fields() {
+        let event = apply("rename attributes.attributes.usr.id user.id", fixture());
+        assert_eq!(event["user"]["id"], 42);
+        assert!(event["attributes"]["attributes"]["usr"].get("id").is_none());
+
+        // Nothing to move
+        let before = event.clone();
+        assert_eq!(apply("rename missing other", event), before);
+    }
+
+    #[test]
+    fn keeps_fields_that_cant_be_renamed() {
+        // The service is a string, so nothing can be set inside it
+        let before = fixture();
+        let event = apply("rename attributes.host attributes.service.host", fixture());
+        assert_eq!(event, before);
+    }
+
+    #[test]
+    fn drops_fields() {
+        let event = apply(
+            "drop attributes.attributes.debug attributes.host missing",
+            fixture(),
+        );
+        assert!(event["attributes"]["attributes"].get("debug").is_none());
+        assert!(event["attributes"].get("host").is_none());
+        assert_eq!(event["attributes"]["service"], "api");
+    }
+
+    #[test]
+    fn parses_json_in_place() {
+        let event = apply("parse-json attributes.attributes.payload", fixture());
+        assert_eq!(
+            event["attributes"]["attributes"]["payload"],
+            json!({"user": "bob", "retries": 2})
+        );
+
+        // Strings that aren't json are left alone
+        let event = apply("parse-json attributes.message", event);
+        assert_eq!(event["attributes"]["message"], "GET /users took 35ms");
+    }
+
+    #[test]
+    fn parses_json_into_another_field() {
+        let event = apply("parse-json attributes.attributes.payload parsed", fixture());
+        assert_eq!(event["parsed"]["user"], "bob");
+        assert!(event["attributes"]["attributes"]["payload"].is_string());
+    }
+
+    #[test]
+    fn extracts_named_groups() {
+        let step = r"extract attributes.message timing (?P<method>[A-Z]+) \S+ took (?P<ms>\d+)ms";
+        let event = apply(step, fixture());
+        assert_eq!(event["timing"], json!({"method": "GET", "ms": "35"}));
+
+        // Fields already under INTO are kept
+        let mut event = fixture();
+        event["timing"] = json!({"unit": "ms"});
+        let event = apply(step, event);
+        assert_eq!(
+            event["timing"],
+            json!({"unit": "ms", "method": "GET", "ms": "35"})
+        );
+
+        // Neither a regex that doesn't match, nor an INTO that isn't an object, changes anything
+        let before = fixture();
+        assert_eq!(
+            apply(
+                r"extract attributes.message timing (?P<ms>\d+)s",
+                before.clone()
+            ),
+            before
+        );
+        let before = apply("set timing {attributes.host}", fixture());
+        assert_eq!(apply(step, before.clone()), before);
+    }
+
+    #[test]
+    fn sets_fields_from_templates() {
+        let event = apply(
+            "set origin {attributes.service}@{attributes.host}",
+            fixture(),
+        );
+        assert_eq!(event["origin"], "api@web-1");
+
+        // A template that's just one field keeps its type
+        let event = apply("set user_id {attributes.attributes.usr.id}", event);
+        assert_eq!(event["user_id"], 42);
+
+        let event = apply("set braces {{{attributes.service}}}", event);
+        assert_eq!(event["braces"], "{api}");
+
+        // A missing field means there's nothing to set
+        let event = apply(
+            "set origin {attributes.service}@{attributes.missing}",
+            event,
+        );
+        assert_eq!(event["origin"], "api@web-1");
+        let event = apply("set other {attributes.missing}", event);
+        assert!(event.get("other").is_none());
+    }
+
+    #[test]
+    fn runs_steps_in_order() {
+        let pipeline: Pipeline = "
+            # Comments and blank lines are skipped
+
+            rename attributes.attributes.usr.id user_id
+            set who user-{user_id}
+        "
+        .parse()
+        .unwrap();
+        let mut event = fixture();
+        pipeline.apply(&mut event);
+        assert_eq!(event["who"], "user-42");
+    }
+
+    #[test]
+    fn rejects_invalid_steps() {
+        let e = error("rename a b\nexplode a");
+        assert!(
+            e.contains("line 2") && e.contains("Unknown step explode"),
+            "{}",
+            e
+        );
+        let e = error("set a {b}}");
+        assert!(e.contains("Unmatched }"), "{}", e);
+        let e = error("set a {b");
+        assert!(e.contains("Unclosed {"), "{}", e);
+        let e = error("set a x{}");
+        assert!(e.contains("Empty field"), "{}", e);
+        let e = error(r"extract a b (\d+)ms");
+        assert!(e.contains("no named groups"), "{}", e);
+        let e = error(r"extract a b (?P<ms>\d+");
+        assert!(e.contains("Invalid regex"), "{}", e);
+        for usage in [
+            "rename a",
+            "drop",
+            "parse-json",
+            "parse-json a b c",
+            "extract a b",
+            "set a",
+        ] {
+            assert!(error(usage).contains("Expected"), "{:?}", usage);
+        }
+    }
+}